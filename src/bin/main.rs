@@ -1,26 +1,49 @@
 use mime_guess::from_path;
-use pretty_env_logger;
-use scratch::net::http::{Request, Response, Server, Status};
-use std::fs;
-use std::io::Result;
+use scratch::net::activation;
+use scratch::net::http::{Body, HeaderName, Method, Request, Response, Router, Server, Status};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
 
 const PUBLIC: &str = "public";
 
 fn main() -> Result<()> {
   pretty_env_logger::init();
-  Server::bind("127.0.0.1:8001").serve(handle_request)
+
+  let router = Router::new()
+    .get("/*path", serve_file)
+    .route(Method::HEAD, "/*path", serve_file);
+
+  // Under systemd socket activation the unit owns the port.
+  let mut passed = activation::listen_fds()?;
+  if passed.len() > 1 {
+    return Err(Error::new(
+      ErrorKind::InvalidInput,
+      format!("expected one socket from systemd, got {}", passed.len()),
+    ));
+  }
+  let server = match passed.pop() {
+    Some((_, listener)) => Server::from_tcp(listener),
+    None => Server::bind("127.0.0.1:8001"),
+  };
+
+  server.handle_signals().serve(router)
 }
 
-fn handle_request<'a>(request: Request) -> Result<Response> {
-  let file_path = format!("{}{}", PUBLIC, request.url().path());
+fn serve_file(request: Request) -> Result<Response> {
+  let path = match request.param("path") {
+    Some("") | None => "index.html",
+    Some(path) => path,
+  };
+  let file_path = format!("{}/{}", PUBLIC, path);
 
-  match fs::read(&file_path) {
+  // Sent with sendfile(2) rather than read into memory.
+  match File::open(&file_path).and_then(Body::from_file) {
     Ok(content) => {
       let mime_type_guess = from_path(&file_path).first_raw().unwrap_or("text/plain");
       Ok(
         Response::builder()
           .body(content)
-          .header(("Content-Type".to_string(), mime_type_guess.to_string()))
+          .header((HeaderName::CONTENT_TYPE, mime_type_guess))
           .into(),
       )
     }
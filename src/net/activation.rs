@@ -0,0 +1,175 @@
+use super::tcp::{Socket, TcpListener};
+use super::util::{getsockopt_int, into_io_error};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::libc;
+use nix::unistd::{getpid, Pid};
+use std::env;
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+/// The first inherited file descriptor; the others follow it.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// The name systemd reports for sockets without a `FileDescriptorName=`.
+const UNKNOWN_NAME: &str = "unknown";
+
+/// Takes over the listening TCP sockets systemd passed for socket
+/// activation (see `sd_listen_fds(3)`), each with its
+/// `FileDescriptorName=` (or `"unknown"`). Returns nothing when the
+/// process was not socket activated, or when `LISTEN_PID` names another
+/// process.
+///
+/// The `LISTEN_*` variables are removed so child processes do not try to
+/// adopt the sockets as well, which also means only the first call finds
+/// them. Fails if an inherited descriptor is not a listening stream socket
+/// for IPv4 or IPv6.
+pub fn listen_fds() -> IoResult<Vec<(String, TcpListener<Socket>)>> {
+  let pid = env::var("LISTEN_PID").ok();
+  let fds = env::var("LISTEN_FDS").ok();
+  let names = env::var("LISTEN_FDNAMES").ok();
+  env::remove_var("LISTEN_PID");
+  env::remove_var("LISTEN_FDS");
+  env::remove_var("LISTEN_FDNAMES");
+
+  let count = passed_count(pid.as_deref(), fds.as_deref(), getpid())?;
+  let fds: Vec<RawFd> = (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count)).collect();
+  let names = fd_names(names.as_deref(), fds.len())?;
+  adopt(&fds, &names)
+}
+
+/// How many descriptors the `LISTEN_PID` and `LISTEN_FDS` values pass to
+/// the process `own_pid`.
+fn passed_count(pid: Option<&str>, fds: Option<&str>, own_pid: Pid) -> IoResult<RawFd> {
+  match pid {
+    Some(pid) if pid.trim() == own_pid.to_string() => {}
+    _ => return Ok(0),
+  }
+  match fds {
+    Some(fds) => fds
+      .trim()
+      .parse::<RawFd>()
+      .map(|count| count.max(0))
+      .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid LISTEN_FDS")),
+    None => Ok(0),
+  }
+}
+
+/// Splits the colon separated `LISTEN_FDNAMES` value into one name for each
+/// of the `count` descriptors.
+fn fd_names(names: Option<&str>, count: usize) -> IoResult<Vec<&str>> {
+  match names {
+    Some(names) if !names.is_empty() => {
+      let names: Vec<&str> = names.split(':').collect();
+      if names.len() != count {
+        return Err(Error::new(
+          ErrorKind::InvalidData,
+          "LISTEN_FDNAMES does not match LISTEN_FDS",
+        ));
+      }
+      Ok(names)
+    }
+    _ => Ok(vec![UNKNOWN_NAME; count]),
+  }
+}
+
+/// Adopts `fds`, each named after the name at the same position in `names`.
+fn adopt(fds: &[RawFd], names: &[&str]) -> IoResult<Vec<(String, TcpListener<Socket>)>> {
+  // Validate every descriptor before taking ownership of any, so a bad one
+  // does not leave the others closed behind our back.
+  for &fd in fds {
+    check_listening_stream(fd)?;
+  }
+
+  let mut listeners = Vec::new();
+  for (&fd, name) in fds.iter().zip(names) {
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(into_io_error)?;
+    // Safe because the fd was handed to this process for it to own.
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    listeners.push((name.to_string(), TcpListener::from_inner(socket)));
+  }
+  Ok(listeners)
+}
+
+fn check_listening_stream(fd: RawFd) -> IoResult<()> {
+  let invalid = |reason: &str| {
+    Error::new(
+      ErrorKind::InvalidInput,
+      format!("inherited fd {} {}", fd, reason),
+    )
+  };
+
+  if getsockopt_int(fd, libc::SOL_SOCKET, libc::SO_TYPE)? != libc::SOCK_STREAM {
+    return Err(invalid("is not a stream socket"));
+  }
+  let domain = getsockopt_int(fd, libc::SOL_SOCKET, libc::SO_DOMAIN)?;
+  if domain != libc::AF_INET && domain != libc::AF_INET6 {
+    return Err(invalid("is not an IPv4 or IPv6 socket"));
+  }
+  if getsockopt_int(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN)? == 0 {
+    return Err(invalid("is not listening"));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use nix::unistd::{close, dup};
+  use std::os::unix::io::AsRawFd;
+
+  /// A copy of `fd` on a free descriptor, like the ones systemd passes.
+  fn pass(fd: &dyn AsRawFd) -> RawFd {
+    dup(fd.as_raw_fd()).unwrap()
+  }
+
+  #[test]
+  fn adopts_listening_sockets() {
+    let web = TcpListener::<Socket>::bind("127.0.0.1:0").unwrap();
+    let admin = TcpListener::<Socket>::bind("[::1]:0").unwrap();
+
+    let adopted = adopt(&[pass(&web), pass(&admin)], &["web", "admin"]).unwrap();
+    assert_eq!(adopted[0].0, "web");
+    assert_eq!(adopted[1].0, "admin");
+    assert_eq!(
+      adopted[1].1.local_addr().unwrap(),
+      admin.local_addr().unwrap()
+    );
+
+    let addr = web.local_addr().unwrap();
+    drop(web);
+    std::net::TcpStream::connect(addr).unwrap();
+    assert!(adopted[0].1.accept().is_ok());
+  }
+
+  #[test]
+  fn rejects_what_cannot_be_served() {
+    let listener = TcpListener::<Socket>::bind("127.0.0.1:0").unwrap();
+    let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let fds = [pass(&listener), pass(&udp), pass(&client)];
+
+    assert!(adopt(&fds[1..2], &[UNKNOWN_NAME]).is_err());
+    assert!(adopt(&fds[2..], &[UNKNOWN_NAME]).is_err());
+    assert!(adopt(&fds, &[UNKNOWN_NAME; 3]).is_err());
+    for fd in fds.iter() {
+      close(*fd).unwrap();
+    }
+
+    assert!(adopt(&[], &[]).unwrap().is_empty());
+  }
+
+  #[test]
+  fn reads_what_was_passed() {
+    let me = Pid::from_raw(42);
+    assert_eq!(passed_count(Some("42"), Some("2"), me).unwrap(), 2);
+    assert!(passed_count(Some("42"), Some("two"), me).is_err());
+    assert_eq!(passed_count(Some("42"), None, me).unwrap(), 0);
+    // Sockets for another process, most likely our parent.
+    assert_eq!(passed_count(Some("1"), Some("2"), me).unwrap(), 0);
+    assert_eq!(passed_count(None, Some("2"), me).unwrap(), 0);
+
+    assert_eq!(fd_names(Some("web:admin"), 2).unwrap(), ["web", "admin"]);
+    assert_eq!(fd_names(None, 2).unwrap(), [UNKNOWN_NAME; 2]);
+    assert!(fd_names(Some("only-one"), 2).is_err());
+  }
+}
@@ -0,0 +1,417 @@
+use crate::net::tcp::{SocketLike, TcpStream};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Read, Result as IoResult, Write};
+use std::os::unix::fs::FileExt;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+/// Most bytes handed to one `sendfile(2)` call.
+const SEND_FILE_CHUNK: usize = 1024 * 1024;
+
+/// The payload of a response. Besides plain bytes it can stream from a
+/// reader, a file or an iterator of chunks, so large bodies never have to be
+/// held in memory as a whole. File bodies go from the page cache straight
+/// to the socket with `sendfile(2)` where the socket supports it. Bodies of
+/// unknown length are sent with `Transfer-Encoding: chunked`.
+#[derive(Default)]
+pub struct Body {
+  kind: Kind,
+}
+
+#[derive(Default)]
+enum Kind {
+  #[default]
+  Empty,
+  Bytes(Vec<u8>),
+  Reader {
+    reader: Box<dyn Read + Send>,
+    length: Option<u64>,
+    /// Bytes already read, to hold the reader to `length`.
+    read: u64,
+  },
+  Chunks(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+  File {
+    file: File,
+    offset: u64,
+    remaining: u64,
+  },
+}
+
+impl Body {
+  pub fn empty() -> Self {
+    Body::default()
+  }
+
+  /// A body streamed from `reader`. Pass `length` if it is known up front so
+  /// it can be sent as `Content-Length` instead of chunked. The reader is
+  /// then held to it: reading stops after `length` bytes, and ending early
+  /// fails with `ErrorKind::UnexpectedEof`.
+  pub fn from_reader(reader: impl Read + Send + 'static, length: Option<u64>) -> Self {
+    Body {
+      kind: Kind::Reader {
+        reader: Box::new(reader),
+        length,
+        read: 0,
+      },
+    }
+  }
+
+  /// The whole content of `file`, whose size is taken from its metadata.
+  pub fn from_file(file: File) -> IoResult<Self> {
+    let length = file.metadata()?.len();
+    Body::from_file_range(file, 0, length)
+  }
+
+  /// `length` bytes of `file` starting at `offset`, e.g. to answer a range
+  /// request. Fails if `file` is not a regular file or the range goes past
+  /// its end.
+  pub fn from_file_range(file: File, offset: u64, length: u64) -> IoResult<Self> {
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+      return Err(Error::new(ErrorKind::InvalidInput, "not a regular file"));
+    }
+    match offset.checked_add(length) {
+      Some(end) if end <= metadata.len() => {}
+      _ => {
+        return Err(Error::new(
+          ErrorKind::InvalidInput,
+          "range goes past the end of the file",
+        ))
+      }
+    }
+    Ok(Body {
+      kind: Kind::File {
+        file,
+        offset,
+        remaining: length,
+      },
+    })
+  }
+
+  /// A body made of the chunks yielded by `chunks`, sent as they come.
+  pub fn from_chunks(chunks: impl Iterator<Item = Vec<u8>> + Send + 'static) -> Self {
+    Body {
+      kind: Kind::Chunks(Box::new(chunks)),
+    }
+  }
+
+  /// The length of the body in bytes, if known before sending it.
+  pub fn len(&self) -> Option<u64> {
+    match &self.kind {
+      Kind::Empty => Some(0),
+      Kind::Bytes(bytes) => Some(bytes.len() as u64),
+      Kind::Reader { length, .. } => *length,
+      Kind::Chunks(_) => None,
+      Kind::File { remaining, .. } => Some(*remaining),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == Some(0)
+  }
+
+  /// The body's content if it is held in memory.
+  pub fn as_bytes(&self) -> Option<&[u8]> {
+    match &self.kind {
+      Kind::Empty => Some(&[]),
+      Kind::Bytes(bytes) => Some(bytes),
+      _ => None,
+    }
+  }
+
+  /// Returns the next piece of the body, or `None` once it is exhausted.
+  /// Never returns an empty piece.
+  pub(crate) fn next_chunk(&mut self) -> IoResult<Option<Vec<u8>>> {
+    match &mut self.kind {
+      Kind::Empty => Ok(None),
+      Kind::Bytes(_) => match std::mem::take(&mut self.kind) {
+        Kind::Bytes(bytes) if !bytes.is_empty() => Ok(Some(bytes)),
+        _ => Ok(None),
+      },
+      Kind::Reader {
+        reader,
+        length,
+        read: total,
+      } => {
+        let limit = match *length {
+          Some(length) if *total >= length => return Ok(None),
+          Some(length) => (length - *total).min(CHUNK_SIZE as u64) as usize,
+          None => CHUNK_SIZE,
+        };
+        let mut chunk = vec![0; limit];
+        loop {
+          match reader.read(&mut chunk) {
+            Ok(0) if length.is_some() => {
+              return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "reader ended before the announced length",
+              ))
+            }
+            Ok(0) => return Ok(None),
+            Ok(read) => {
+              *total += read as u64;
+              chunk.truncate(read);
+              return Ok(Some(chunk));
+            }
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+          }
+        }
+      }
+      Kind::Chunks(chunks) => Ok(chunks.find(|chunk| !chunk.is_empty())),
+      Kind::File {
+        file,
+        offset,
+        remaining,
+      } => {
+        if *remaining == 0 {
+          return Ok(None);
+        }
+        let mut chunk = vec![0; (*remaining).min(CHUNK_SIZE as u64) as usize];
+        let read = loop {
+          match file.read_at(&mut chunk, *offset) {
+            Ok(read) => break read,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+          }
+        };
+        advance(offset, remaining, read)?;
+        chunk.truncate(read);
+        Ok(Some(chunk))
+      }
+    }
+  }
+
+  /// Whether file content is left to send with `send_file_to`.
+  fn has_file_data(&self) -> bool {
+    matches!(self.kind, Kind::File { remaining, .. } if remaining > 0)
+  }
+
+  /// Sends the next part of a file body straight to `stream`, returning how
+  /// many bytes went out.
+  fn send_file_to<S: SocketLike>(&mut self, stream: &TcpStream<S>) -> IoResult<usize> {
+    match &mut self.kind {
+      Kind::File {
+        file,
+        offset,
+        remaining,
+      } => {
+        let count = (*remaining).min(SEND_FILE_CHUNK as u64) as usize;
+        let sent = stream.send_file(file, *offset, count)?;
+        advance(offset, remaining, sent)?;
+        Ok(sent)
+      }
+      _ => Ok(0),
+    }
+  }
+}
+
+/// Moves a file body past `done` bytes. Nothing done while some remain
+/// means the file shrank after its length was announced.
+fn advance(offset: &mut u64, remaining: &mut u64, done: usize) -> IoResult<()> {
+  if done == 0 {
+    return Err(Error::new(
+      ErrorKind::UnexpectedEof,
+      "file ended before the announced length",
+    ));
+  }
+  *offset += done as u64;
+  *remaining -= done as u64;
+  Ok(())
+}
+
+impl fmt::Debug for Body {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self.kind {
+      Kind::Empty => write!(f, "Body::Empty"),
+      Kind::Bytes(bytes) => write!(f, "Body::Bytes({} bytes)", bytes.len()),
+      Kind::Reader { length, .. } => write!(f, "Body::Reader({:?})", length),
+      Kind::Chunks(_) => write!(f, "Body::Chunks"),
+      Kind::File {
+        offset, remaining, ..
+      } => write!(f, "Body::File({} bytes at {})", remaining, offset),
+    }
+  }
+}
+
+impl From<Vec<u8>> for Body {
+  fn from(bytes: Vec<u8>) -> Self {
+    Body {
+      kind: Kind::Bytes(bytes),
+    }
+  }
+}
+
+impl From<String> for Body {
+  fn from(text: String) -> Self {
+    Body::from(text.into_bytes())
+  }
+}
+
+impl From<&str> for Body {
+  fn from(text: &str) -> Self {
+    Body::from(text.as_bytes().to_vec())
+  }
+}
+
+/// Turns a response head and body into the pieces written to the wire,
+/// adding chunked framing when asked to.
+pub(crate) struct BodyEncoder {
+  head: Option<Vec<u8>>,
+  body: Body,
+  chunked: bool,
+  done: bool,
+}
+
+impl BodyEncoder {
+  pub fn new(head: Vec<u8>, body: Body, chunked: bool) -> Self {
+    BodyEncoder {
+      head: Some(head),
+      body,
+      chunked,
+      done: false,
+    }
+  }
+
+  /// Returns the next bytes to send, or `None` once everything was handed
+  /// out.
+  pub fn next_chunk(&mut self) -> IoResult<Option<Vec<u8>>> {
+    if let Some(head) = self.head.take() {
+      return Ok(Some(head));
+    }
+    if self.done {
+      return Ok(None);
+    }
+
+    match self.body.next_chunk()? {
+      Some(chunk) if self.chunked => {
+        let mut framed = format!("{:X}\r\n", chunk.len()).into_bytes();
+        framed.extend_from_slice(&chunk);
+        framed.extend_from_slice(b"\r\n");
+        Ok(Some(framed))
+      }
+      Some(chunk) => Ok(Some(chunk)),
+      None => {
+        self.done = true;
+        if self.chunked {
+          Ok(Some(b"0\r\n\r\n".to_vec()))
+        } else {
+          Ok(None)
+        }
+      }
+    }
+  }
+
+  pub fn write_to<W: Write>(mut self, writer: &mut W) -> IoResult<()> {
+    while let Some(chunk) = self.next_chunk()? {
+      writer.write_all(&chunk)?;
+    }
+    Ok(())
+  }
+
+  /// Whether the next bytes to send are file content `send_file` can hand
+  /// to the kernel. Chunked file bodies need framing and are copied.
+  pub fn file_pending(&self) -> bool {
+    self.head.is_none() && !self.chunked && self.body.has_file_data()
+  }
+
+  /// Sends the next part of a pending file body to `stream`. See
+  /// `file_pending`.
+  pub fn send_file<S: SocketLike>(&mut self, stream: &TcpStream<S>) -> IoResult<usize> {
+    self.body.send_file_to(stream)
+  }
+
+  /// Writes everything to a blocking `stream`, using `sendfile(2)` for file
+  /// bodies.
+  pub fn send_to<S: SocketLike>(mut self, stream: &TcpStream<S>) -> IoResult<()> {
+    let mut writer = BufWriter::new(stream);
+    loop {
+      if self.file_pending() {
+        writer.flush()?;
+        match self.send_file(stream) {
+          Ok(_) => continue,
+          Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+          Err(err) => return Err(err),
+        }
+      }
+      match self.next_chunk()? {
+        Some(chunk) => writer.write_all(&chunk)?,
+        None => return writer.flush(),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn encode(body: Body, chunked: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    BodyEncoder::new(b"HEAD\r\n\r\n".to_vec(), body, chunked)
+      .write_to(&mut out)
+      .unwrap();
+    out
+  }
+
+  #[test]
+  fn knows_length_of_sized_bodies() {
+    assert_eq!(Body::empty().len(), Some(0));
+    assert_eq!(Body::from("hello").len(), Some(5));
+    assert_eq!(
+      Body::from_reader(Cursor::new(vec![1, 2]), Some(2)).len(),
+      Some(2)
+    );
+    assert_eq!(Body::from_chunks(Vec::new().into_iter()).len(), None);
+  }
+
+  #[test]
+  fn streams_reader_as_is() {
+    let body = Body::from_reader(Cursor::new(b"streamed".to_vec()), Some(8));
+    assert_eq!(encode(body, false), b"HEAD\r\n\r\nstreamed");
+  }
+
+  #[test]
+  fn holds_readers_to_their_length() {
+    let long = Body::from_reader(Cursor::new(b"streamed and more".to_vec()), Some(8));
+    assert_eq!(encode(long, false), b"HEAD\r\n\r\nstreamed");
+
+    let short = Body::from_reader(Cursor::new(b"short".to_vec()), Some(8));
+    let err = BodyEncoder::new(Vec::new(), short, false)
+      .write_to(&mut Vec::new())
+      .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+  }
+
+  fn temp_file(name: &str, content: &[u8]) -> File {
+    let path = std::env::temp_dir().join(format!("scratch-{}-{}", name, std::process::id()));
+    std::fs::write(&path, content).unwrap();
+    let file = File::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file
+  }
+
+  #[test]
+  fn copies_file_ranges() {
+    let file = temp_file("range", b"0123456789");
+    let body = Body::from_file_range(file.try_clone().unwrap(), 2, 5).unwrap();
+    assert_eq!(body.len(), Some(5));
+    assert_eq!(encode(body, false), b"HEAD\r\n\r\n23456");
+    let body = Body::from_file_range(file.try_clone().unwrap(), 2, 5).unwrap();
+    assert_eq!(encode(body, true), b"HEAD\r\n\r\n5\r\n23456\r\n0\r\n\r\n");
+
+    assert!(Body::from_file_range(file.try_clone().unwrap(), 8, 3).is_err());
+    assert!(Body::from_file(File::open(std::env::temp_dir()).unwrap()).is_err());
+  }
+
+  #[test]
+  fn frames_chunks_and_skips_empty_ones() {
+    let chunks = vec![b"Wiki".to_vec(), Vec::new(), b"pedia".to_vec()];
+    let body = Body::from_chunks(chunks.into_iter());
+    assert_eq!(
+      encode(body, true),
+      b"HEAD\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n".to_vec()
+    );
+  }
+}
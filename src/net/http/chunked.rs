@@ -0,0 +1,207 @@
+use super::headers::{parse_field, Headers};
+use super::reader::FramingError;
+
+/// Longest chunk-size line (size plus extensions) we are willing to buffer.
+const MAX_CHUNK_LINE: usize = 4096;
+/// Upper bound for the whole trailer section.
+const MAX_TRAILER_BYTES: usize = 16 * 1024;
+
+enum State {
+  Size,
+  Data(usize),
+  DataEnd,
+  Trailer,
+  Done,
+}
+
+/// Incrementally decodes a `Transfer-Encoding: chunked` body (RFC 7230,
+/// 4.1). Chunk extensions are accepted and ignored; trailer fields are kept.
+pub(crate) struct ChunkedDecoder {
+  state: State,
+  body: Vec<u8>,
+  trailers: Headers,
+  trailer_bytes: usize,
+  max_body_bytes: usize,
+}
+
+impl ChunkedDecoder {
+  pub fn new(max_body_bytes: usize) -> Self {
+    ChunkedDecoder {
+      state: State::Size,
+      body: Vec::new(),
+      trailers: Headers::default(),
+      trailer_bytes: 0,
+      max_body_bytes,
+    }
+  }
+
+  /// Consumes as much of `buf` as possible. Returns `true` once the last
+  /// chunk and the trailer section have been read; anything after them is
+  /// left in `buf`.
+  pub fn decode(&mut self, buf: &mut Vec<u8>) -> Result<bool, FramingError> {
+    let mut pos = 0;
+    let result = self.advance(buf, &mut pos);
+    buf.drain(..pos);
+    result
+  }
+
+  /// Hands out the decoded body and trailer fields.
+  pub fn finish(self) -> (Vec<u8>, Headers) {
+    (self.body, self.trailers)
+  }
+
+  fn advance(&mut self, buf: &[u8], pos: &mut usize) -> Result<bool, FramingError> {
+    loop {
+      match self.state {
+        State::Size => {
+          let line = match next_line(&buf[*pos..], MAX_CHUNK_LINE, || FramingError::Malformed)? {
+            Some(line) => line,
+            None => return Ok(false),
+          };
+          *pos += line.len() + 2;
+
+          let size = parse_chunk_size(line)?;
+          if size > self.max_body_bytes - self.body.len() {
+            return Err(FramingError::BodyTooLarge);
+          }
+          self.state = if size == 0 {
+            State::Trailer
+          } else {
+            State::Data(size)
+          };
+        }
+        State::Data(remaining) => {
+          let available = (buf.len() - *pos).min(remaining);
+          if available == 0 {
+            return Ok(false);
+          }
+          self.body.extend_from_slice(&buf[*pos..*pos + available]);
+          *pos += available;
+          self.state = if available == remaining {
+            State::DataEnd
+          } else {
+            State::Data(remaining - available)
+          };
+        }
+        State::DataEnd => {
+          if buf.len() - *pos < 2 {
+            return Ok(false);
+          }
+          if &buf[*pos..*pos + 2] != b"\r\n" {
+            return Err(FramingError::Malformed);
+          }
+          *pos += 2;
+          self.state = State::Size;
+        }
+        State::Trailer => {
+          let limit = MAX_TRAILER_BYTES - self.trailer_bytes;
+          let line = match next_line(&buf[*pos..], limit, || FramingError::TrailersTooLarge)? {
+            Some(line) => line,
+            None => return Ok(false),
+          };
+          *pos += line.len() + 2;
+          self.trailer_bytes += line.len() + 2;
+
+          if line.is_empty() {
+            self.state = State::Done;
+          } else {
+            let (name, value) = parse_field(line).map_err(|_| FramingError::Malformed)?;
+            self.trailers.append(name, value);
+          }
+        }
+        State::Done => return Ok(true),
+      }
+    }
+  }
+}
+
+/// Returns the next CRLF-terminated line without its terminator, failing
+/// with `too_long()` once it exceeds `limit` bytes.
+fn next_line(
+  buf: &[u8],
+  limit: usize,
+  too_long: fn() -> FramingError,
+) -> Result<Option<&[u8]>, FramingError> {
+  match buf.windows(2).position(|w| w == b"\r\n") {
+    Some(end) if end > limit => Err(too_long()),
+    Some(end) => Ok(Some(&buf[..end])),
+    None if buf.len() > limit => Err(too_long()),
+    None => Ok(None),
+  }
+}
+
+/// Parses `chunk-size [ chunk-ext ]`, ignoring the extensions after
+/// checking they are printable.
+fn parse_chunk_size(line: &[u8]) -> Result<usize, FramingError> {
+  let line = std::str::from_utf8(line).map_err(|_| FramingError::Malformed)?;
+  let (size, extensions) = match line.split_once(';') {
+    Some((size, extensions)) => (size.trim_end_matches(&[' ', '\t'][..]), extensions),
+    None => (line, ""),
+  };
+
+  if extensions.chars().any(|c| c.is_control() && c != '\t') {
+    return Err(FramingError::Malformed);
+  }
+  if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+    return Err(FramingError::Malformed);
+  }
+  // Too many digits to fit a usize is certainly too large.
+  usize::from_str_radix(size, 16).map_err(|_| FramingError::BodyTooLarge)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn decode_all(input: &[u8], max: usize) -> Result<(Vec<u8>, Headers, Vec<u8>), FramingError> {
+    let mut decoder = ChunkedDecoder::new(max);
+    let mut buf = Vec::new();
+    // Feed one byte at a time to exercise every partial state.
+    for (i, byte) in input.iter().enumerate() {
+      buf.push(*byte);
+      if decoder.decode(&mut buf)? {
+        buf.extend_from_slice(&input[i + 1..]);
+        let (body, trailers) = decoder.finish();
+        return Ok((body, trailers, buf));
+      }
+    }
+    Err(FramingError::Malformed)
+  }
+
+  #[test]
+  fn decodes_chunks_split_anywhere() {
+    let input = b"4\r\nWiki\r\n5;name=value\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\n\r\nGET";
+    let (body, _, rest) = decode_all(input, 1024).unwrap();
+    assert_eq!(body, b"Wikipedia in\r\n\r\nchunks.");
+    assert_eq!(rest, b"GET");
+  }
+
+  #[test]
+  fn keeps_trailer_fields() {
+    let input = b"3\r\nabc\r\n0\r\nExpires: never\r\nX-Checksum:  42\r\n\r\n";
+    let (body, trailers, _) = decode_all(input, 1024).unwrap();
+    assert_eq!(body, b"abc");
+    assert_eq!(trailers.get("x-checksum"), Some("42"));
+    assert_eq!(trailers.get("Expires"), Some("never"));
+  }
+
+  #[test]
+  fn enforces_the_body_limit() {
+    let input = b"8\r\n12345678\r\n8\r\n12345678\r\n0\r\n\r\n";
+    assert!(matches!(
+      decode_all(input, 10),
+      Err(FramingError::BodyTooLarge)
+    ));
+    assert!(matches!(
+      decode_all(b"ffffffffffffffffffff\r\n", 10),
+      Err(FramingError::BodyTooLarge)
+    ));
+  }
+
+  #[test]
+  fn rejects_malformed_chunks() {
+    assert!(decode_all(b"zz\r\n", 1024).is_err());
+    assert!(decode_all(b"3\r\nabcd\r\n0\r\n\r\n", 1024).is_err());
+    assert!(decode_all(b"0\r\nbad trailer\r\n\r\n", 1024).is_err());
+  }
+}
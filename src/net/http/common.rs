@@ -1,120 +1,359 @@
-use std::collections::HashMap;
+use super::response::Status;
 use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug)]
-pub struct ParseError();
+/// What was wrong with a request head.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+  /// The method is not a token.
+  InvalidMethod,
+  /// The method is well formed but not one we implement.
+  UnknownMethod,
+  InvalidTarget,
+  UriTooLong,
+  InvalidVersion,
+  /// The version is well formed but not HTTP/1.x.
+  UnsupportedVersion,
+  InvalidHeader,
+  HeadersTooLarge,
+  /// The input ended before the request head did.
+  Incomplete,
+}
+
+impl ParseErrorKind {
+  /// The status to answer a request that failed this way with.
+  pub fn status(&self) -> Status {
+    match self {
+      ParseErrorKind::UnknownMethod => Status::NotImplemented,
+      ParseErrorKind::UriTooLong => Status::RequestURITooLong,
+      ParseErrorKind::UnsupportedVersion => Status::HTTPVersionNotSupported,
+      ParseErrorKind::HeadersTooLarge => Status::RequestHeaderFieldsTooLarge,
+      _ => Status::BadRequest,
+    }
+  }
+
+  fn description(&self) -> &'static str {
+    match self {
+      ParseErrorKind::InvalidMethod => "Invalid method",
+      ParseErrorKind::UnknownMethod => "Unknown method",
+      ParseErrorKind::InvalidTarget => "Invalid request target",
+      ParseErrorKind::UriTooLong => "Request target too long",
+      ParseErrorKind::InvalidVersion => "Invalid HTTP version",
+      ParseErrorKind::UnsupportedVersion => "Unsupported HTTP version",
+      ParseErrorKind::InvalidHeader => "Malformed header field",
+      ParseErrorKind::HeadersTooLarge => "Header section too large",
+      ParseErrorKind::Incomplete => "Incomplete request head",
+    }
+  }
+}
+
+/// Why a request head could not be parsed, and the byte offset into the
+/// input where the problem was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError {
+  kind: ParseErrorKind,
+  offset: usize,
+}
+
+impl ParseError {
+  pub fn new(kind: ParseErrorKind, offset: usize) -> Self {
+    ParseError { kind, offset }
+  }
+
+  pub fn kind(&self) -> ParseErrorKind {
+    self.kind
+  }
+
+  pub fn offset(&self) -> usize {
+    self.offset
+  }
+
+  /// Shorthand for `kind().status()`.
+  pub fn status(&self) -> Status {
+    self.kind.status()
+  }
+
+  /// Moves the offset of an error found in a slice starting `start` bytes
+  /// into the input.
+  pub(crate) fn at(self, start: usize) -> Self {
+    ParseError::new(self.kind, start + self.offset)
+  }
+}
 
 impl fmt::Display for ParseError {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "Unable to parse input")
+    write!(f, "{} at byte {}", self.kind.description(), self.offset)
   }
 }
 
+impl std::error::Error for ParseError {}
+
+/// A `tchar` from RFC 7230, 3.2.6.
+pub(crate) fn is_token(byte: u8) -> bool {
+  byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// The position of the first occurrence of `needle` in `haystack`.
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+  haystack
+    .windows(needle.len())
+    .position(|window| window == needle)
+}
+
 pub trait Parse {
   fn parse(txt: &str) -> Result<(&str, Self), ParseError>
   where
     Self: std::marker::Sized;
 }
 
-#[derive(Default, Debug)]
-pub struct Headers {
-  pub map: HashMap<String, String>,
+/// A parsed request-target. The path is percent-decoded and normalized,
+/// the query is split into name/value pairs, and the raw form is kept.
+#[derive(Default, Debug, PartialEq)]
+pub struct Url {
+  raw: String,
+  path: String,
+  query: Option<String>,
+  fragment: Option<String>,
+  query_pairs: Vec<(String, String)>,
 }
 
-type Header = (String, String);
+impl Url {
+  /// The path, percent-decoded, with `.` and `..` segments resolved so it
+  /// never climbs above `/`. An encoded slash (`%2F`) stays encoded so it
+  /// cannot split a segment in two.
+  pub fn path(&self) -> &str {
+    &self.path
+  }
+
+  /// The request-target exactly as the client sent it.
+  pub fn raw(&self) -> &str {
+    &self.raw
+  }
 
-impl Headers {
-  fn parse_one(txt: &str) -> Result<(&str, Header), ParseError> {
-    let mut itr = txt.splitn(2, "\r\n");
+  /// The query without the leading `?`, still encoded.
+  pub fn query_string(&self) -> Option<&str> {
+    self.query.as_deref()
+  }
 
-    let header_line = itr.next().ok_or(ParseError())?;
-    let rest = itr.next().ok_or(ParseError())?;
+  /// The fragment without the leading `#`, still encoded. Clients do not
+  /// normally send one.
+  pub fn fragment(&self) -> Option<&str> {
+    self.fragment.as_deref()
+  }
 
-    let mut kv = header_line.splitn(2, ": ");
+  /// The decoded query parameters in the order they were sent, repeated
+  /// names included.
+  pub fn query_pairs(&self) -> &[(String, String)] {
+    &self.query_pairs
+  }
 
-    let field_name = kv.next().ok_or(ParseError())?.to_string();
-    let field_value = kv.next().ok_or(ParseError())?.to_string();
+  /// The first value of the query parameter `name`.
+  pub fn query<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+    self.query_all(name).next()
+  }
 
-    Ok((rest, (field_name, field_value)))
+  /// Every value of the query parameter `name`, in order.
+  pub fn query_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    self
+      .query_pairs
+      .iter()
+      .filter(move |(key, _)| key == name)
+      .map(|(_, value)| value.as_str())
   }
 }
 
-impl Parse for Headers {
-  fn parse(txt: &str) -> Result<(&str, Self), ParseError> {
-    let mut headers = HashMap::new();
-    let mut rest = txt;
-    loop {
-      if rest.starts_with("\r\n") {
-        let body = rest.splitn(2, "\r\n").nth(1).ok_or(ParseError())?;
-        return Ok((body, Headers { map: headers }));
+impl FromStr for Url {
+  type Err = ParseError;
+
+  /// Parses an origin-form (`/path?query`), absolute-form
+  /// (`http://host/path`) or asterisk-form (`*`) request-target.
+  fn from_str(raw: &str) -> Result<Self, ParseError> {
+    let (rest, fragment) = match raw.split_once('#') {
+      Some((rest, fragment)) => (rest, Some(fragment.to_string())),
+      None => (raw, None),
+    };
+    let (target, query) = match rest.split_once('?') {
+      Some((target, query)) => (target, Some(query.to_string())),
+      None => (rest, None),
+    };
+
+    let path = if target == "*" {
+      target.to_string()
+    } else if target.starts_with('/') {
+      normalize(&decode_path(target)?)
+    } else if let Some(scheme_end) = target.find("://") {
+      let authority_start = scheme_end + "://".len();
+      match target[authority_start..].find('/') {
+        Some(start) => {
+          let start = authority_start + start;
+          normalize(&decode_path(&target[start..]).map_err(|err| err.at(start))?)
+        }
+        None => "/".to_string(),
       }
-      let (txt_rest, (name, value)) = Headers::parse_one(rest)?;
-      headers.insert(name, value);
-      rest = txt_rest;
-    }
+    } else {
+      return Err(ParseError::new(ParseErrorKind::InvalidTarget, 0));
+    };
+
+    let query_pairs = query.as_deref().map(parse_query).unwrap_or_default();
+    Ok(Url {
+      raw: raw.to_string(),
+      path,
+      query,
+      fragment,
+      query_pairs,
+    })
   }
 }
 
-#[derive(Default, Debug, PartialEq)]
-pub struct Url {
-  path: String,
+impl Parse for Url {
+  fn parse(txt: &str) -> Result<(&str, Self), ParseError> {
+    let (url, rest) = txt
+      .split_once(' ')
+      .ok_or_else(|| ParseError::new(ParseErrorKind::InvalidVersion, txt.len()))?;
+
+    Ok((rest, url.parse()?))
+  }
 }
 
-impl Url {
-  pub fn path(&self) -> &str {
-    &self.path
+fn hex_value(byte: u8) -> Option<u8> {
+  (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Decodes `%XX` escapes in a path. Malformed escapes, NUL bytes and
+/// invalid UTF-8 are rejected; `%2F` is left encoded.
+fn decode_path(path: &str) -> Result<String, ParseError> {
+  let invalid = |offset| ParseError::new(ParseErrorKind::InvalidTarget, offset);
+  let bytes = path.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] != b'%' {
+      decoded.push(bytes[i]);
+      i += 1;
+      continue;
+    }
+    let high = bytes.get(i + 1).copied().and_then(hex_value);
+    let low = bytes.get(i + 2).copied().and_then(hex_value);
+    match (high, low) {
+      (Some(0), Some(0)) => return Err(invalid(i)),
+      (Some(high), Some(low)) if high * 16 + low == b'/' => {
+        decoded.extend_from_slice(&bytes[i..i + 3]);
+      }
+      (Some(high), Some(low)) => decoded.push(high * 16 + low),
+      _ => return Err(invalid(i)),
+    }
+    i += 3;
   }
+  // Offsets into the decoded bytes do not map back onto the input.
+  String::from_utf8(decoded).map_err(|_| invalid(0))
 }
 
-impl Parse for Url {
-  fn parse(txt: &str) -> Result<(&str, Self), ParseError> {
-    let mut itr = txt.splitn(2, " ");
+/// Resolves `.` and `..` segments (RFC 3986, 5.2.4), dropping any `..`
+/// that would leave the root.
+fn normalize(path: &str) -> String {
+  let mut segments: Vec<&str> = Vec::new();
+  let mut parts = path.split('/').skip(1).peekable();
+  while let Some(segment) = parts.next() {
+    match segment {
+      "." | ".." => {
+        if segment == ".." {
+          segments.pop();
+        }
+        // A trailing dot segment still names a directory.
+        if parts.peek().is_none() {
+          segments.push("");
+        }
+      }
+      _ => segments.push(segment),
+    }
+  }
+  format!("/{}", segments.join("/"))
+}
 
-    let url = itr.next().ok_or(ParseError())?;
-    let rest = itr.next().ok_or(ParseError())?;
+/// Splits a query into decoded pairs. Unlike the path it is decoded
+/// leniently: `+` means a space, malformed escapes are kept as they are
+/// and invalid UTF-8 is replaced.
+fn parse_query(query: &str) -> Vec<(String, String)> {
+  query
+    .split('&')
+    .filter(|pair| !pair.is_empty())
+    .map(|pair| {
+      let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+      (decode_query(name), decode_query(value))
+    })
+    .collect()
+}
 
-    Ok((
-      rest,
-      Url {
-        path: url.to_string(),
-      },
-    ))
+fn decode_query(text: &str) -> String {
+  let bytes = text.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let escaped = match bytes[i] {
+      b'%' => bytes
+        .get(i + 1)
+        .copied()
+        .and_then(hex_value)
+        .zip(bytes.get(i + 2).copied().and_then(hex_value)),
+      _ => None,
+    };
+    match (bytes[i], escaped) {
+      (_, Some((high, low))) => {
+        decoded.push(high * 16 + low);
+        i += 3;
+      }
+      (b'+', None) => {
+        decoded.push(b' ');
+        i += 1;
+      }
+      (byte, None) => {
+        decoded.push(byte);
+        i += 1;
+      }
+    }
   }
+  String::from_utf8_lossy(&decoded).into_owned()
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Version {
   major: u8,
   minor: u8,
 }
 
 impl Parse for Version {
+  /// Parses `HTTP/x.y` up to the end of the line. Versions other than 1.x
+  /// are well formed but not supported.
   fn parse(txt: &str) -> Result<(&str, Self), ParseError> {
-    let mut itr = txt.splitn(2, "\r\n");
+    let (version, rest) = txt
+      .split_once("\r\n")
+      .ok_or_else(|| ParseError::new(ParseErrorKind::Incomplete, txt.len()))?;
 
-    let version = itr.next().ok_or(ParseError())?;
-    let rest = itr.next().ok_or(ParseError())?;
+    let digits = match version.strip_prefix("HTTP/").map(str::as_bytes) {
+      Some(&[major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit() => {
+        (major - b'0', minor - b'0')
+      }
+      _ => return Err(ParseError::new(ParseErrorKind::InvalidVersion, 0)),
+    };
+    if digits.0 != 1 {
+      return Err(ParseError::new(ParseErrorKind::UnsupportedVersion, 0));
+    }
 
-    let mut version_iter = version
-      .splitn(2, "HTTP/")
-      .skip(1)
-      .next()
-      .ok_or(ParseError())?
-      .split(".");
+    Ok((rest, Version::new(digits.0, digits.1)))
+  }
+}
 
-    let major = version_iter
-      .next()
-      .ok_or(ParseError())?
-      .parse::<u8>()
-      .map_err(|_| ParseError())?;
+impl Version {
+  pub fn new(major: u8, minor: u8) -> Self {
+    Version { major, minor }
+  }
 
-    let minor = version_iter
-      .next()
-      .ok_or(ParseError())?
-      .parse::<u8>()
-      .map_err(|_| ParseError())?;
+  pub fn major(&self) -> u8 {
+    self.major
+  }
 
-    Ok((rest, Version { major, minor }))
+  pub fn minor(&self) -> u8 {
+    self.minor
   }
 }
 
@@ -124,8 +363,83 @@ impl Default for Version {
   }
 }
 
-impl<'a> fmt::Display for Version {
+impl fmt::Display for Version {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     write!(f, "HTTP/{}.{}", self.major, self.minor)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn splits_the_request_target() {
+    let url: Url = "/search%20results/?q=rust+lang&page=2&q=nix&empty&x=%zz#top"
+      .parse()
+      .unwrap();
+    assert_eq!(url.path(), "/search results/");
+    assert_eq!(
+      url.query_string(),
+      Some("q=rust+lang&page=2&q=nix&empty&x=%zz")
+    );
+    assert_eq!(url.fragment(), Some("top"));
+    assert_eq!(url.query("q"), Some("rust lang"));
+    assert_eq!(url.query_all("q").collect::<Vec<_>>(), ["rust lang", "nix"]);
+    assert_eq!(url.query("empty"), Some(""));
+    assert_eq!(url.query("x"), Some("%zz"));
+    assert_eq!(url.query("missing"), None);
+    assert_eq!(url.query_pairs()[1], ("page".to_string(), "2".to_string()));
+    assert!(url.raw().ends_with("#top"));
+  }
+
+  #[test]
+  fn decodes_paths_safely() {
+    let path = |raw: &str| raw.parse::<Url>().map(|url| url.path().to_string());
+    assert_eq!(path("/caf%C3%A9").unwrap(), "/café");
+    assert_eq!(
+      path("/a/%2e%2e/%2E%2E/../etc/passwd").unwrap(),
+      "/etc/passwd"
+    );
+    assert_eq!(path("/a/./b/..").unwrap(), "/a/");
+    assert_eq!(path("/a%2Fb").unwrap(), "/a%2Fb");
+    assert_eq!(path("http://example.com/x?y").unwrap(), "/x");
+    assert_eq!(path("http://example.com").unwrap(), "/");
+    assert_eq!(path("*").unwrap(), "*");
+
+    assert!(path("/%").is_err());
+    assert!(path("/%4").is_err());
+    assert!(path("/%zz").is_err());
+    assert!(path("/nul%00").is_err());
+    assert!(path("/%ff").is_err());
+    assert!(path("relative").is_err());
+  }
+
+  #[test]
+  fn reports_where_targets_are_invalid() {
+    let err = "/ok/%zz".parse::<Url>().unwrap_err();
+    assert_eq!(err.kind(), ParseErrorKind::InvalidTarget);
+    assert_eq!(err.offset(), 4);
+    assert_eq!(err.status(), Status::BadRequest);
+
+    let err = "http://host/a%".parse::<Url>().unwrap_err();
+    assert_eq!(err.offset(), 13);
+  }
+
+  #[test]
+  fn parses_only_http_1() {
+    let parse = |txt: &str| Version::parse(txt).map(|(_, version)| version);
+    assert_eq!(parse("HTTP/1.0\r\n"), Ok(Version::new(1, 0)));
+    assert_eq!(parse("HTTP/1.1\r\n"), Ok(Version::new(1, 1)));
+
+    let kind = |txt: &str| parse(txt).unwrap_err().kind();
+    assert_eq!(kind("HTTP/2.0\r\n"), ParseErrorKind::UnsupportedVersion);
+    assert_eq!(kind("HTTP/1.10\r\n"), ParseErrorKind::InvalidVersion);
+    assert_eq!(kind("http/1.1\r\n"), ParseErrorKind::InvalidVersion);
+    assert_eq!(kind("HTTP/1.1"), ParseErrorKind::Incomplete);
+    assert_eq!(
+      ParseErrorKind::UnsupportedVersion.status(),
+      Status::HTTPVersionNotSupported
+    );
+  }
+}
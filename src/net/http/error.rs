@@ -0,0 +1,114 @@
+use crate::net::http::{HeaderName, Response, Status};
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::Error;
+
+/// An error a handler can return to answer with a specific status instead of
+/// `500 Internal Server Error`. It converts into an `io::Error`, so it can be
+/// returned with `?` or `.into()` from any handler:
+///
+/// ```ignore
+/// return Err(HttpError::new(Status::Forbidden, "Members only").into());
+/// ```
+#[derive(Debug)]
+pub struct HttpError {
+  status: Status,
+  message: String,
+}
+
+impl HttpError {
+  pub fn new(status: Status, message: impl Into<String>) -> Self {
+    HttpError {
+      status,
+      message: message.into(),
+    }
+  }
+
+  pub fn status(&self) -> Status {
+    self.status
+  }
+
+  pub fn message(&self) -> &str {
+    &self.message
+  }
+
+  /// Finds the `HttpError` wrapped in `err`, if there is one.
+  pub fn from_io(err: &Error) -> Option<&HttpError> {
+    err.get_ref()?.downcast_ref()
+  }
+
+  /// A response with this error's status and its message as the body.
+  pub fn response(&self) -> Response {
+    Response::builder()
+      .status(self.status)
+      .header((HeaderName::CONTENT_TYPE, "text/plain"))
+      .body(self.message.clone())
+      .into()
+  }
+}
+
+impl fmt::Display for HttpError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}: {}", self.status, self.message)
+  }
+}
+
+impl StdError for HttpError {}
+
+impl From<HttpError> for Error {
+  fn from(err: HttpError) -> Self {
+    Error::other(err)
+  }
+}
+
+/// Turns the error a handler failed with into the response sent to the
+/// client. Implemented for closures taking `&io::Error`.
+pub trait ErrorMapper: Send + Sync + 'static {
+  fn map_error(&self, err: &Error) -> Response;
+}
+
+impl<F> ErrorMapper for F
+where
+  F: Fn(&Error) -> Response + Send + Sync + 'static,
+{
+  fn map_error(&self, err: &Error) -> Response {
+    self(err)
+  }
+}
+
+/// Answers an `HttpError` with its status and message and anything else
+/// with a bare `500 Internal Server Error`, so internal details never reach
+/// the client.
+pub(crate) fn default_error_response(err: &Error) -> Response {
+  match HttpError::from_io(err) {
+    Some(http_error) => http_error.response(),
+    None => Response::builder()
+      .status(Status::InternalServerError)
+      .into(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn survives_the_trip_through_io_error() {
+    let err: Error = HttpError::new(Status::NotFound, "No such user").into();
+    let http_error = HttpError::from_io(&err).unwrap();
+    assert_eq!(http_error.status(), Status::NotFound);
+    assert_eq!(http_error.message(), "No such user");
+  }
+
+  #[test]
+  fn default_mapping() {
+    let err: Error = HttpError::new(Status::Conflict, "Taken").into();
+    let response = default_error_response(&err);
+    assert_eq!(response.status().code(), 409);
+    assert_eq!(response.body().as_bytes(), Some(&b"Taken"[..]));
+
+    let response = default_error_response(&Error::other("database is down"));
+    assert_eq!(response.status().code(), 500);
+    assert!(response.body().is_empty());
+  }
+}
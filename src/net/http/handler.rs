@@ -0,0 +1,17 @@
+use crate::net::http::{Request, Response};
+use std::io::Result as IoResult;
+
+/// Turns a request into a response. Implemented for any suitable closure or
+/// function, as well as for `Router`.
+pub trait Handler: Send + Sync + 'static {
+  fn handle(&self, request: Request) -> IoResult<Response>;
+}
+
+impl<F> Handler for F
+where
+  F: Fn(Request) -> IoResult<Response> + Send + Sync + 'static,
+{
+  fn handle(&self, request: Request) -> IoResult<Response> {
+    self(request)
+  }
+}
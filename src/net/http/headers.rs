@@ -0,0 +1,297 @@
+use super::common::{find, is_token, Parse, ParseError, ParseErrorKind};
+use std::borrow::Cow;
+use std::fmt;
+
+/// A header field name. Names compare equal regardless of ASCII case but
+/// keep the spelling they were created with for serialization.
+#[derive(Debug, Clone)]
+pub struct HeaderName(Cow<'static, str>);
+
+impl HeaderName {
+  pub const ACCEPT: HeaderName = HeaderName::from_static("Accept");
+  pub const ALLOW: HeaderName = HeaderName::from_static("Allow");
+  pub const CACHE_CONTROL: HeaderName = HeaderName::from_static("Cache-Control");
+  pub const CONNECTION: HeaderName = HeaderName::from_static("Connection");
+  pub const CONTENT_LENGTH: HeaderName = HeaderName::from_static("Content-Length");
+  pub const CONTENT_TYPE: HeaderName = HeaderName::from_static("Content-Type");
+  pub const COOKIE: HeaderName = HeaderName::from_static("Cookie");
+  pub const DATE: HeaderName = HeaderName::from_static("Date");
+  pub const EXPECT: HeaderName = HeaderName::from_static("Expect");
+  pub const HOST: HeaderName = HeaderName::from_static("Host");
+  pub const LOCATION: HeaderName = HeaderName::from_static("Location");
+  pub const SERVER: HeaderName = HeaderName::from_static("Server");
+  pub const SET_COOKIE: HeaderName = HeaderName::from_static("Set-Cookie");
+  pub const TRANSFER_ENCODING: HeaderName = HeaderName::from_static("Transfer-Encoding");
+  pub const USER_AGENT: HeaderName = HeaderName::from_static("User-Agent");
+
+  pub const fn from_static(name: &'static str) -> Self {
+    HeaderName(Cow::Borrowed(name))
+  }
+
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+}
+
+impl PartialEq for HeaderName {
+  fn eq(&self, other: &Self) -> bool {
+    self.0.eq_ignore_ascii_case(&other.0)
+  }
+}
+
+impl Eq for HeaderName {}
+
+impl PartialEq<str> for HeaderName {
+  fn eq(&self, other: &str) -> bool {
+    self.0.eq_ignore_ascii_case(other)
+  }
+}
+
+impl AsRef<str> for HeaderName {
+  fn as_ref(&self) -> &str {
+    &self.0
+  }
+}
+
+impl From<&str> for HeaderName {
+  fn from(name: &str) -> Self {
+    HeaderName(Cow::Owned(name.to_string()))
+  }
+}
+
+impl From<String> for HeaderName {
+  fn from(name: String) -> Self {
+    HeaderName(Cow::Owned(name))
+  }
+}
+
+impl From<&HeaderName> for HeaderName {
+  fn from(name: &HeaderName) -> Self {
+    name.clone()
+  }
+}
+
+impl fmt::Display for HeaderName {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+/// Header fields in the order they were added. Lookups ignore the case of
+/// the name and a name may appear more than once, like `Set-Cookie`.
+#[derive(Default, Debug, Clone)]
+pub struct Headers {
+  fields: Vec<(HeaderName, String)>,
+}
+
+impl Headers {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  /// Parses header fields up to and including the blank line that ends
+  /// them, returning the bytes after it.
+  pub(crate) fn parse_bytes(raw: &[u8]) -> Result<(&[u8], Self), ParseError> {
+    let mut headers = Headers::new();
+    let mut rest = raw;
+    loop {
+      let start = raw.len() - rest.len();
+      let end = find(rest, b"\r\n")
+        .ok_or_else(|| ParseError::new(ParseErrorKind::Incomplete, raw.len()))?;
+      let line = &rest[..end];
+      rest = &rest[end + 2..];
+      if line.is_empty() {
+        return Ok((rest, headers));
+      }
+      let (name, value) = parse_field(line).map_err(|err| err.at(start))?;
+      headers.append(name, value);
+    }
+  }
+
+  /// The first value of the header `name`.
+  pub fn get(&self, name: impl AsRef<str>) -> Option<&str> {
+    self.get_all(name).next()
+  }
+
+  /// Every value of the header `name`, in the order they were added.
+  pub fn get_all(&self, name: impl AsRef<str>) -> impl Iterator<Item = &str> {
+    self
+      .fields
+      .iter()
+      .filter(move |(key, _)| *key == *name.as_ref())
+      .map(|(_, value)| value.as_str())
+  }
+
+  pub fn contains(&self, name: impl AsRef<str>) -> bool {
+    self.get(name).is_some()
+  }
+
+  /// Sets a header, replacing every existing value for the name. The header
+  /// keeps the position of the first value it replaces.
+  pub fn insert(&mut self, name: impl Into<HeaderName>, value: impl Into<String>) {
+    let name = name.into();
+    let mut value = Some(value.into());
+    self.fields.retain_mut(|(key, existing)| {
+      if *key != name {
+        return true;
+      }
+      match value.take() {
+        Some(value) => {
+          *existing = value;
+          true
+        }
+        None => false,
+      }
+    });
+    if let Some(value) = value {
+      self.fields.push((name, value));
+    }
+  }
+
+  /// Adds a value for `name` after any existing ones.
+  pub fn append(&mut self, name: impl Into<HeaderName>, value: impl Into<String>) {
+    self.fields.push((name.into(), value.into()));
+  }
+
+  /// Removes every value of the header `name`, returning the first.
+  pub fn remove(&mut self, name: impl AsRef<str>) -> Option<String> {
+    let name = name.as_ref();
+    let mut removed = None;
+    self.fields.retain_mut(|(key, value)| {
+      if *key != *name {
+        return true;
+      }
+      if removed.is_none() {
+        removed = Some(std::mem::take(value));
+      }
+      false
+    });
+    removed
+  }
+
+  /// The fields in insertion order, one entry per value.
+  pub fn iter(&self) -> impl Iterator<Item = (&HeaderName, &str)> {
+    self
+      .fields
+      .iter()
+      .map(|(name, value)| (name, value.as_str()))
+  }
+
+  pub fn len(&self) -> usize {
+    self.fields.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.fields.is_empty()
+  }
+}
+
+impl Parse for Headers {
+  fn parse(txt: &str) -> Result<(&str, Self), ParseError> {
+    let (rest, headers) = Headers::parse_bytes(txt.as_bytes())?;
+    // The rest follows a line break, so it starts on a char boundary.
+    Ok((&txt[txt.len() - rest.len()..], headers))
+  }
+}
+
+/// Splits a `name: value` line. The name must be a token, which keeps it
+/// ASCII; the value may not contain control characters other than tab, and
+/// bytes that are not UTF-8 are replaced.
+pub(crate) fn parse_field(line: &[u8]) -> Result<(&str, Cow<'_, str>), ParseError> {
+  let invalid = |offset| ParseError::new(ParseErrorKind::InvalidHeader, offset);
+  let colon = line
+    .iter()
+    .position(|&b| b == b':')
+    .ok_or_else(|| invalid(line.len()))?;
+  let (name, value) = (&line[..colon], &line[colon + 1..]);
+  if let Some(bad) = name.iter().position(|&b| !is_token(b)) {
+    return Err(invalid(bad));
+  }
+  if name.is_empty() {
+    return Err(invalid(0));
+  }
+  if let Some(bad) = value
+    .iter()
+    .position(|&b| (b < b' ' && b != b'\t') || b == 0x7f)
+  {
+    return Err(invalid(colon + 1 + bad));
+  }
+
+  let is_space = |b: &u8| *b == b' ' || *b == b'\t';
+  let start = value
+    .iter()
+    .position(|b| !is_space(b))
+    .unwrap_or(value.len());
+  let end = value
+    .iter()
+    .rposition(|b| !is_space(b))
+    .map_or(start, |end| end + 1);
+  let name = std::str::from_utf8(name).map_err(|_| invalid(0))?;
+  Ok((name, String::from_utf8_lossy(&value[start..end])))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn names_ignore_case() {
+    let mut headers = Headers::new();
+    headers.insert("content-length", "1");
+    headers.insert(HeaderName::CONTENT_LENGTH, "2");
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers.get("CONTENT-LENGTH"), Some("2"));
+    assert_eq!(headers.iter().next().unwrap().0.as_str(), "content-length");
+  }
+
+  #[test]
+  fn keeps_repeated_fields_in_order() {
+    let (_, mut headers) =
+      Headers::parse("Host: a\r\nSet-Cookie: x=1\r\nAccept: */*\r\nset-cookie: y=2\r\n\r\n")
+        .unwrap();
+    assert_eq!(
+      headers.get_all(HeaderName::SET_COOKIE).collect::<Vec<_>>(),
+      ["x=1", "y=2"]
+    );
+
+    headers.append("Host", "b");
+    let names: Vec<_> = headers.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+      names,
+      ["Host", "Set-Cookie", "Accept", "set-cookie", "Host"]
+    );
+
+    headers.insert("set-cookie", "z=3");
+    let fields: Vec<_> = headers
+      .iter()
+      .map(|(name, value)| format!("{}: {}", name, value))
+      .collect();
+    assert_eq!(
+      fields,
+      ["Host: a", "Set-Cookie: z=3", "Accept: */*", "Host: b"]
+    );
+
+    assert_eq!(headers.remove("HOST"), Some("a".to_string()));
+    assert!(!headers.contains("host"));
+    assert_eq!(headers.len(), 2);
+  }
+
+  #[test]
+  fn parses_fields_strictly() {
+    let (name, value) = parse_field(b"X-Name:\t caf\xc3\xa9 \t").unwrap();
+    assert_eq!((name, value.as_ref()), ("X-Name", "café"));
+    assert_eq!(parse_field(b"Empty:").unwrap().1, "");
+
+    assert!(parse_field(b"No colon").is_err());
+    assert!(parse_field(b": no name").is_err());
+    assert!(parse_field(b"Space before : colon").is_err());
+    assert!(parse_field(b"Caf\xc3\xa9: name").is_err());
+    assert!(parse_field(b"Bell: \x07").is_err());
+
+    let err = Headers::parse("Host: a\r\nBad Name: b\r\n\r\n").unwrap_err();
+    assert_eq!(err.kind(), ParseErrorKind::InvalidHeader);
+    assert_eq!(err.offset(), 12);
+    let err = Headers::parse("Host: a\r\n").unwrap_err();
+    assert_eq!(err.kind(), ParseErrorKind::Incomplete);
+  }
+}
@@ -0,0 +1,95 @@
+use super::router::Pattern;
+
+const DEFAULT_MAX_REQUEST_LINE: usize = 8 << 10;
+const DEFAULT_MAX_HEADERS: usize = 100;
+const DEFAULT_MAX_HEADER_BYTES: usize = 1 << 20;
+const DEFAULT_MAX_BODY_BYTES: usize = 8 << 20;
+
+/// How large a request may be. A request line that is too long is answered
+/// with 414, too many or too large headers with 431 and a body that is too
+/// large with 413, and the connection is closed since the rest of the
+/// request is never read.
+///
+/// ```ignore
+/// Server::bind("127.0.0.1:8000").limits(
+///   Limits::default()
+///     .max_body_bytes(1 << 20)
+///     .max_body_bytes_for("/upload/*file", 64 << 20),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct Limits {
+  max_request_line: usize,
+  max_headers: usize,
+  max_header_bytes: usize,
+  max_body_bytes: usize,
+  routes: Vec<(Pattern, usize)>,
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Limits {
+      max_request_line: DEFAULT_MAX_REQUEST_LINE,
+      max_headers: DEFAULT_MAX_HEADERS,
+      max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+      max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+      routes: Vec::new(),
+    }
+  }
+}
+
+impl Limits {
+  /// The longest request line, without its line break. Defaults to 8 KiB.
+  pub fn max_request_line(mut self, bytes: usize) -> Self {
+    self.max_request_line = bytes;
+    self
+  }
+
+  /// How many header fields a request may have. Defaults to 100.
+  pub fn max_headers(mut self, count: usize) -> Self {
+    self.max_headers = count;
+    self
+  }
+
+  /// The size of all header lines together, line breaks included. Defaults
+  /// to 1 MiB.
+  pub fn max_header_bytes(mut self, bytes: usize) -> Self {
+    self.max_header_bytes = bytes;
+    self
+  }
+
+  /// The largest body of a request no `max_body_bytes_for` route matches.
+  /// Defaults to 8 MiB.
+  pub fn max_body_bytes(mut self, bytes: usize) -> Self {
+    self.max_body_bytes = bytes;
+    self
+  }
+
+  /// The largest body of a request whose path matches `pattern`, written
+  /// like a `Router` pattern. The first matching route wins.
+  pub fn max_body_bytes_for(mut self, pattern: &str, bytes: usize) -> Self {
+    self.routes.push((Pattern::parse(pattern), bytes));
+    self
+  }
+
+  pub(crate) fn request_line(&self) -> usize {
+    self.max_request_line
+  }
+
+  pub(crate) fn headers(&self) -> usize {
+    self.max_headers
+  }
+
+  pub(crate) fn header_bytes(&self) -> usize {
+    self.max_header_bytes
+  }
+
+  /// The body limit for a request to `path`.
+  pub(crate) fn body_bytes(&self, path: &str) -> usize {
+    self
+      .routes
+      .iter()
+      .find(|(pattern, _)| pattern.matches(path).is_some())
+      .map_or(self.max_body_bytes, |(_, bytes)| *bytes)
+  }
+}
@@ -0,0 +1,101 @@
+use crate::net::memory::MemorySocket;
+use crate::net::tcp::{Socket, SocketLike, TcpListener, TcpStream};
+use crate::net::unix::{PeerCredentials, UnixListener, UnixSocket};
+use nix::sys::socket::SockFlag;
+use std::io::Result as IoResult;
+use std::time::Duration;
+
+/// The socket a `Server` accepts connections on.
+pub(crate) enum Listener {
+  Tcp(TcpListener<Socket>),
+  Unix(UnixListener),
+  Memory(TcpListener<MemorySocket>),
+}
+
+impl Listener {
+  /// The address we listen on, for log messages.
+  pub fn describe(&self) -> IoResult<String> {
+    match self {
+      Listener::Tcp(listener) => listener.local_addr().map(|addr| addr.to_string()),
+      Listener::Unix(listener) => listener.local_addr().map(|addr| format!("unix:{}", addr)),
+      Listener::Memory(listener) => listener.local_addr().map(|addr| format!("memory:{}", addr)),
+    }
+  }
+}
+
+/// What the connection loops need from a listening socket, so they work the
+/// same on TCP and Unix sockets.
+pub(crate) trait Accept: Sync {
+  type Socket: SocketLike + Send + Sync + 'static;
+
+  /// Accepts a connection with the given `accept4(2)` flags, along with the
+  /// credentials of the process on the other end where the socket type
+  /// provides them.
+  fn accept_connection(
+    &self,
+    flags: SockFlag,
+  ) -> IoResult<(TcpStream<Self::Socket>, Option<PeerCredentials>)>;
+  fn poll_accept(&self, timeout: Duration) -> IoResult<bool>;
+  fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()>;
+}
+
+impl Accept for TcpListener<Socket> {
+  type Socket = Socket;
+
+  fn accept_connection(
+    &self,
+    flags: SockFlag,
+  ) -> IoResult<(TcpStream<Socket>, Option<PeerCredentials>)> {
+    let (stream, _) = self.accept_with(flags)?;
+    Ok((stream, None))
+  }
+
+  fn poll_accept(&self, timeout: Duration) -> IoResult<bool> {
+    TcpListener::poll_accept(self, timeout)
+  }
+
+  fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+    TcpListener::set_nonblocking(self, nonblocking)
+  }
+}
+
+impl Accept for UnixListener {
+  type Socket = UnixSocket;
+
+  fn accept_connection(
+    &self,
+    flags: SockFlag,
+  ) -> IoResult<(TcpStream<UnixSocket>, Option<PeerCredentials>)> {
+    let (stream, _) = self.accept_with(flags)?;
+    let credentials = stream.peer_credentials()?;
+    Ok((stream, Some(credentials)))
+  }
+
+  fn poll_accept(&self, timeout: Duration) -> IoResult<bool> {
+    UnixListener::poll_accept(self, timeout)
+  }
+
+  fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+    UnixListener::set_nonblocking(self, nonblocking)
+  }
+}
+
+impl Accept for TcpListener<MemorySocket> {
+  type Socket = MemorySocket;
+
+  fn accept_connection(
+    &self,
+    flags: SockFlag,
+  ) -> IoResult<(TcpStream<MemorySocket>, Option<PeerCredentials>)> {
+    let (stream, _) = self.accept_with(flags)?;
+    Ok((stream, None))
+  }
+
+  fn poll_accept(&self, timeout: Duration) -> IoResult<bool> {
+    TcpListener::poll_accept(self, timeout)
+  }
+
+  fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+    TcpListener::set_nonblocking(self, nonblocking)
+  }
+}
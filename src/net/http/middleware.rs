@@ -0,0 +1,157 @@
+use crate::net::http::{Handler, Request, Response};
+use std::io::Result as IoResult;
+use std::sync::Arc;
+
+/// Wraps request handling with cross-cutting behaviour. A middleware gets
+/// the request together with `next`, the rest of the chain. It can change
+/// the request before passing it on, change the response `next` returns, or
+/// answer on its own without calling `next` at all.
+///
+/// Implemented for closures taking `(Request, Next)`.
+pub trait Middleware: Send + Sync + 'static {
+  fn call(&self, request: Request, next: Next) -> IoResult<Response>;
+}
+
+impl<F> Middleware for F
+where
+  F: Fn(Request, Next) -> IoResult<Response> + Send + Sync + 'static,
+{
+  fn call(&self, request: Request, next: Next) -> IoResult<Response> {
+    self(request, next)
+  }
+}
+
+/// The remainder of a middleware chain, ending in the handler.
+pub struct Next<'a> {
+  middleware: &'a [Arc<dyn Middleware>],
+  endpoint: &'a dyn Handler,
+}
+
+impl<'a> Next<'a> {
+  /// Passes the request on to the next middleware, or to the handler if
+  /// this was the last one.
+  pub fn run(self, request: Request) -> IoResult<Response> {
+    match self.middleware.split_first() {
+      Some((middleware, rest)) => middleware.call(
+        request,
+        Next {
+          middleware: rest,
+          endpoint: self.endpoint,
+        },
+      ),
+      None => self.endpoint.handle(request),
+    }
+  }
+}
+
+/// A handler wrapped in middleware. Middleware runs in the order it was
+/// added: the first one sees the request first and the response last.
+pub struct Stack<H> {
+  middleware: Vec<Arc<dyn Middleware>>,
+  endpoint: H,
+}
+
+impl<H: Handler> Stack<H> {
+  pub fn new(endpoint: H) -> Self {
+    Stack {
+      middleware: Vec::new(),
+      endpoint,
+    }
+  }
+
+  pub(crate) fn with_middleware(middleware: Vec<Arc<dyn Middleware>>, endpoint: H) -> Self {
+    Stack {
+      middleware,
+      endpoint,
+    }
+  }
+
+  /// Adds `middleware` inside of the ones added before it.
+  pub fn wrap(mut self, middleware: impl Middleware) -> Self {
+    self.middleware.push(Arc::new(middleware));
+    self
+  }
+}
+
+impl<H: Handler> Handler for Stack<H> {
+  fn handle(&self, request: Request) -> IoResult<Response> {
+    Next {
+      middleware: &self.middleware,
+      endpoint: &self.endpoint,
+    }
+    .run(request)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::net::http::Status;
+  use std::sync::Mutex;
+
+  fn request(path: &str) -> Request {
+    Request::parse(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes()).unwrap()
+  }
+
+  #[test]
+  fn runs_in_order() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let tracer = |name: &'static str| {
+      let log = Arc::clone(&log);
+      move |request: Request, next: Next| {
+        log.lock().unwrap().push(format!("{} before", name));
+        let response = next.run(request);
+        log.lock().unwrap().push(format!("{} after", name));
+        response
+      }
+    };
+
+    let handler_log = Arc::clone(&log);
+    let stack = Stack::new(move |_| {
+      handler_log.lock().unwrap().push("handler".to_string());
+      Ok(Response::builder().into())
+    })
+    .wrap(tracer("outer"))
+    .wrap(tracer("inner"));
+
+    stack.handle(request("/")).unwrap();
+    assert_eq!(
+      *log.lock().unwrap(),
+      vec![
+        "outer before",
+        "inner before",
+        "handler",
+        "inner after",
+        "outer after"
+      ]
+    );
+  }
+
+  #[test]
+  fn short_circuits() {
+    let stack = Stack::new(|_| panic!("handler must not run"))
+      .wrap(|request: Request, next: Next| {
+        if request.url().path() == "/admin" {
+          return Ok(Response::builder().status(Status::Forbidden).into());
+        }
+        next.run(request)
+      })
+      .wrap(|_: Request, _: Next| panic!("inner middleware must not run"));
+
+    let response = stack.handle(request("/admin")).unwrap();
+    assert_eq!(response.status().code(), 403);
+  }
+
+  #[test]
+  fn changes_the_response() {
+    let stack =
+      Stack::new(|_| Ok(Response::builder().into())).wrap(|request: Request, next: Next| {
+        let mut response = next.run(request)?;
+        response.headers_mut().insert("X-Frame-Options", "DENY");
+        Ok(response)
+      });
+
+    let response = stack.handle(request("/")).unwrap();
+    assert_eq!(response.headers().get("x-frame-options"), Some("DENY"));
+  }
+}
@@ -1,9 +1,33 @@
+mod body;
+mod chunked;
 mod common;
+mod error;
+mod handler;
+mod headers;
+mod limits;
+mod listener;
+mod middleware;
+mod pool;
+mod reactor;
+mod reader;
 mod request;
 mod response;
+mod router;
 mod server;
+mod shutdown;
 
+pub use body::Body;
+pub use common::{ParseError, ParseErrorKind};
+pub use error::{ErrorMapper, HttpError};
+pub use handler::Handler;
+pub use headers::{HeaderName, Headers};
+pub use limits::Limits;
+pub use middleware::{Middleware, Next, Stack};
+pub use request::Method;
 pub use request::Request;
 pub use response::Response;
 pub use response::Status;
+pub use router::{Params, Router};
+pub use server::Backend;
 pub use server::Server;
+pub use shutdown::ShutdownHandle;
@@ -0,0 +1,167 @@
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads fed through a bounded queue. Once the queue
+/// is full, `execute` blocks until a worker picks up the next job, which is
+/// what pushes back on the accept loop.
+pub struct ThreadPool {
+  sender: Option<SyncSender<Job>>,
+  workers: Vec<Worker>,
+}
+
+impl ThreadPool {
+  pub fn new(size: usize, queue_size: usize) -> IoResult<ThreadPool> {
+    let (sender, receiver) = sync_channel(queue_size);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    let mut workers = Vec::with_capacity(size);
+    for id in 0..size.max(1) {
+      workers.push(Worker::spawn(id, Arc::clone(&receiver))?);
+    }
+
+    Ok(ThreadPool {
+      sender: Some(sender),
+      workers,
+    })
+  }
+
+  pub fn execute<F>(&self, job: F) -> IoResult<()>
+  where
+    F: FnOnce() + Send + 'static,
+  {
+    match &self.sender {
+      Some(sender) => sender
+        .send(Box::new(job))
+        .map_err(|_| Error::new(ErrorKind::BrokenPipe, "worker pool has shut down")),
+      None => Err(Error::new(
+        ErrorKind::BrokenPipe,
+        "worker pool has shut down",
+      )),
+    }
+  }
+
+  /// Stops taking jobs and waits up to `timeout` for the workers to finish
+  /// the ones already queued. Returns `false` if some workers were still busy
+  /// at the deadline; those are left running detached.
+  pub fn join(mut self, timeout: Duration) -> bool {
+    drop(self.sender.take());
+
+    let deadline = Instant::now() + timeout;
+    while self.workers.iter().any(|w| !w.thread.is_finished()) {
+      if Instant::now() >= deadline {
+        let busy = self
+          .workers
+          .iter()
+          .filter(|w| !w.thread.is_finished())
+          .count();
+        warn!("Abandoning {} busy worker(s) after shutdown timeout", busy);
+        self.workers.retain(|w| w.thread.is_finished());
+        return false;
+      }
+      thread::sleep(JOIN_POLL_INTERVAL);
+    }
+    true
+  }
+}
+
+impl Drop for ThreadPool {
+  fn drop(&mut self) {
+    // Closing the channel lets every worker finish its queued jobs and exit.
+    drop(self.sender.take());
+
+    for worker in self.workers.drain(..) {
+      if worker.thread.join().is_err() {
+        error!("Worker {} panicked", worker.id);
+      }
+    }
+  }
+}
+
+struct Worker {
+  id: usize,
+  thread: JoinHandle<()>,
+}
+
+impl Worker {
+  fn spawn(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> IoResult<Worker> {
+    let thread = thread::Builder::new()
+      .name(format!("scratch-worker-{}", id))
+      .spawn(move || loop {
+        let job = match receiver.lock() {
+          Ok(receiver) => receiver.recv(),
+          Err(_) => return,
+        };
+
+        match job {
+          Ok(job) => {
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+              error!("Worker {} recovered from a panicking job", id);
+            }
+          }
+          Err(_) => return,
+        }
+      })?;
+
+    Ok(Worker { id, thread })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[test]
+  fn runs_every_job_before_dropping() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    {
+      let pool = ThreadPool::new(3, 2).unwrap();
+      for _ in 0..20 {
+        let counter = Arc::clone(&counter);
+        pool
+          .execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+          })
+          .unwrap();
+      }
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), 20);
+  }
+
+  #[test]
+  fn survives_panicking_jobs() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    {
+      let pool = ThreadPool::new(1, 1).unwrap();
+      pool.execute(|| panic!("boom")).unwrap();
+      let counter = Arc::clone(&counter);
+      pool
+        .execute(move || {
+          counter.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn join_gives_up_at_the_deadline() {
+    let pool = ThreadPool::new(1, 1).unwrap();
+    pool
+      .execute(|| thread::sleep(Duration::from_millis(500)))
+      .unwrap();
+    assert!(!pool.join(Duration::from_millis(20)));
+
+    let pool = ThreadPool::new(1, 1).unwrap();
+    pool.execute(|| {}).unwrap();
+    assert!(pool.join(Duration::from_secs(5)));
+  }
+}
@@ -0,0 +1,716 @@
+use crate::net::http::body::BodyEncoder;
+use crate::net::http::listener::Accept;
+use crate::net::http::reader::{FramingError, RequestDecoder, RequestTimer};
+use crate::net::http::server::{respond, ConnectionConfig, LINGER_TIMEOUT, SHUTDOWN_POLL_INTERVAL};
+use crate::net::http::shutdown::ShutdownHandle;
+use crate::net::http::Handler;
+use crate::net::tcp::*;
+use crate::net::unix::PeerCredentials;
+use crate::net::util::into_io_error;
+use nix::errno::Errno;
+use nix::sys::epoll::{
+  epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+};
+use nix::sys::socket::SockFlag;
+use nix::unistd::close;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Result as IoResult, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LISTENER: u64 = u64::MAX;
+const SHUTDOWN: u64 = u64::MAX - 1;
+const MAX_EVENTS: usize = 1024;
+const READ_CHUNK: usize = 16 * 1024;
+/// How much encoded output is buffered before handing it to the socket.
+const WRITE_BATCH: usize = 64 * 1024;
+/// How often idle connections are swept when nothing else is happening.
+const SWEEP_INTERVAL_MS: isize = 1000;
+
+/// Runs `threads` event loops over `listener` until shutdown is requested
+/// and they have drained. If one of them fails the others are shut down as
+/// well and its error is returned. Every loop registers the listener with
+/// `EPOLLEXCLUSIVE` so a new connection only wakes one of them, and owns the
+/// connections it accepts.
+pub(crate) fn run<L, H>(
+  listener: &L,
+  config: &ConnectionConfig,
+  handler: &H,
+  threads: usize,
+  shutdown_timeout: Duration,
+) -> IoResult<()>
+where
+  L: Accept + AsRawFd,
+  L::Socket: AsRawFd,
+  H: Handler,
+{
+  listener.set_nonblocking(true)?;
+
+  thread::scope(|scope| {
+    let mut loops = Vec::with_capacity(threads);
+    let mut result = Ok(());
+    for id in 0..threads.max(1) {
+      let spawned = thread::Builder::new()
+        .name(format!("scratch-reactor-{}", id))
+        .spawn_scoped(scope, move || {
+          // However this loop ends, the others must stop too or joining
+          // them below would wait for an external shutdown.
+          let _stop = StopOnExit(&config.shutdown);
+          Reactor::new(listener, config, handler, shutdown_timeout)?.run()
+        });
+      match spawned {
+        Ok(handle) => loops.push(handle),
+        Err(err) => {
+          config.shutdown.shutdown();
+          result = Err(err);
+          break;
+        }
+      }
+    }
+
+    for handle in loops {
+      let joined = handle
+        .join()
+        .unwrap_or_else(|_| Err(Error::other("event loop panicked")));
+      if result.is_ok() {
+        result = joined;
+      }
+    }
+    result
+  })
+}
+
+/// Requests shutdown when dropped, so an event loop that fails or panics
+/// takes the others down with it.
+struct StopOnExit<'a>(&'a ShutdownHandle);
+
+impl<'a> Drop for StopOnExit<'a> {
+  fn drop(&mut self) {
+    self.0.shutdown();
+  }
+}
+
+struct Reactor<'a, L: Accept, H> {
+  epoll: RawFd,
+  listener: &'a L,
+  config: &'a ConnectionConfig,
+  handler: &'a H,
+  connections: HashMap<RawFd, Connection<L::Socket>>,
+  shutdown_timeout: Duration,
+  /// Deadline for open connections once shutdown has been requested.
+  draining: Option<Instant>,
+  /// When to watch the listener again after accepting failed.
+  accept_paused: Option<Instant>,
+}
+
+impl<'a, L, H> Reactor<'a, L, H>
+where
+  L: Accept + AsRawFd,
+  L::Socket: AsRawFd,
+  H: Handler,
+{
+  fn new(
+    listener: &'a L,
+    config: &'a ConnectionConfig,
+    handler: &'a H,
+    shutdown_timeout: Duration,
+  ) -> IoResult<Self> {
+    let epoll = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC).map_err(into_io_error)?;
+    let reactor = Reactor {
+      epoll,
+      listener,
+      config,
+      handler,
+      connections: HashMap::new(),
+      shutdown_timeout,
+      draining: None,
+      accept_paused: None,
+    };
+    reactor.watch_listener()?;
+
+    let mut event = EpollEvent::new(EpollFlags::EPOLLIN, SHUTDOWN);
+    epoll_ctl(
+      reactor.epoll,
+      EpollOp::EpollCtlAdd,
+      config.shutdown.event_fd(),
+      &mut event,
+    )
+    .map_err(into_io_error)?;
+
+    Ok(reactor)
+  }
+
+  fn run(&mut self) -> IoResult<()> {
+    let mut events = vec![EpollEvent::empty(); MAX_EVENTS];
+
+    loop {
+      let timeout = match self.draining {
+        Some(deadline) if self.connections.is_empty() || Instant::now() >= deadline => {
+          if !self.connections.is_empty() {
+            warn!(
+              "Closing {} connection(s) after shutdown timeout",
+              self.connections.len()
+            );
+          }
+          return Ok(());
+        }
+        Some(deadline) => {
+          let remaining = deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis();
+          (remaining as isize).clamp(1, SWEEP_INTERVAL_MS)
+        }
+        None => SWEEP_INTERVAL_MS,
+      };
+      let timeout = match self.accept_paused {
+        Some(until) => {
+          let remaining = until.saturating_duration_since(Instant::now()).as_millis();
+          timeout.min((remaining as isize).max(1))
+        }
+        None => timeout,
+      };
+
+      let ready = match epoll_wait(self.epoll, &mut events, timeout) {
+        Ok(ready) => ready,
+        Err(err) if err.as_errno() == Some(Errno::EINTR) => continue,
+        Err(err) => return Err(into_io_error(err)),
+      };
+
+      for event in &events[..ready] {
+        if event.data() == LISTENER {
+          self.accept_all();
+        } else if event.data() == SHUTDOWN {
+          self.start_draining();
+        } else {
+          self.on_event(event.data() as RawFd, event.events());
+        }
+      }
+
+      self.resume_accepting()?;
+      self.close_idle();
+    }
+  }
+
+  fn watch_listener(&self) -> IoResult<()> {
+    let mut event = EpollEvent::new(EpollFlags::EPOLLIN | EpollFlags::EPOLLEXCLUSIVE, LISTENER);
+    epoll_ctl(
+      self.epoll,
+      EpollOp::EpollCtlAdd,
+      self.listener.as_raw_fd(),
+      &mut event,
+    )
+    .map_err(into_io_error)
+  }
+
+  /// Stops watching the listener for a while. It stays readable while
+  /// accepting fails, e.g. because we ran out of file descriptors, and would
+  /// otherwise wake the loop over and over.
+  fn pause_accepting(&mut self) {
+    let _ = epoll_ctl(
+      self.epoll,
+      EpollOp::EpollCtlDel,
+      self.listener.as_raw_fd(),
+      None,
+    );
+    self.accept_paused = Some(Instant::now() + SHUTDOWN_POLL_INTERVAL);
+  }
+
+  fn resume_accepting(&mut self) -> IoResult<()> {
+    match self.accept_paused {
+      Some(until) if Instant::now() >= until => {
+        self.accept_paused = None;
+        // Draining stopped watching the listener for good.
+        if self.draining.is_none() {
+          self.watch_listener()?;
+        }
+        Ok(())
+      }
+      _ => Ok(()),
+    }
+  }
+
+  fn accept_all(&mut self) {
+    loop {
+      match self.listener.accept_connection(SockFlag::SOCK_NONBLOCK) {
+        Ok((stream, credentials)) => {
+          if let Err(err) = self.register(stream, credentials) {
+            error!("Error registering connection: {}", err);
+          }
+        }
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
+        Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+        Err(err) => {
+          error!("Error accepting connection: {}", err);
+          self.pause_accepting();
+          return;
+        }
+      }
+    }
+  }
+
+  fn register(
+    &mut self,
+    stream: TcpStream<L::Socket>,
+    credentials: Option<PeerCredentials>,
+  ) -> IoResult<()> {
+    let fd = stream.as_raw_fd();
+    let mut event = EpollEvent::new(EpollFlags::EPOLLIN, fd as u64);
+    epoll_ctl(self.epoll, EpollOp::EpollCtlAdd, fd, &mut event).map_err(into_io_error)?;
+    self
+      .connections
+      .insert(fd, Connection::new(stream, credentials, self.config));
+    Ok(())
+  }
+
+  fn on_event(&mut self, fd: RawFd, flags: EpollFlags) {
+    let conn = match self.connections.get_mut(&fd) {
+      Some(conn) => conn,
+      None => return,
+    };
+
+    let result = conn.on_ready(flags, self.config, self.handler);
+    match result {
+      Ok(()) if !conn.finished() => {
+        let interest = conn.interest();
+        if interest != conn.interest {
+          let mut event = EpollEvent::new(interest, fd as u64);
+          match epoll_ctl(self.epoll, EpollOp::EpollCtlMod, fd, &mut event) {
+            Ok(()) => conn.interest = interest,
+            Err(err) => {
+              error!("Error updating connection interest: {}", err);
+              self.deregister(fd);
+            }
+          }
+        }
+      }
+      Ok(()) => self.deregister(fd),
+      Err(err) => {
+        error!("Error handling connection: {}", err);
+        self.deregister(fd);
+      }
+    }
+  }
+
+  /// Stops accepting and gives the remaining connections until the shutdown
+  /// deadline to finish what they are doing.
+  fn start_draining(&mut self) {
+    if self.draining.is_some() {
+      return;
+    }
+    let _ = epoll_ctl(
+      self.epoll,
+      EpollOp::EpollCtlDel,
+      self.listener.as_raw_fd(),
+      None,
+    );
+    let _ = epoll_ctl(
+      self.epoll,
+      EpollOp::EpollCtlDel,
+      self.config.shutdown.event_fd(),
+      None,
+    );
+    self.draining = Some(Instant::now() + self.shutdown_timeout);
+  }
+
+  fn close_idle(&mut self) {
+    let timeout = self.config.keep_alive_timeout;
+    let write_timeout = self.config.write_timeout;
+    let draining = self.draining.is_some();
+    let mut idle = Vec::new();
+    let mut stalled = Vec::new();
+    let mut timed_out = Vec::new();
+
+    for (fd, conn) in &self.connections {
+      if let Linger::Until(deadline) = conn.linger {
+        if Instant::now() >= deadline {
+          idle.push(*fd);
+        }
+      } else if conn.has_pending_write() {
+        if matches!(write_timeout, Some(t) if conn.write_progress.elapsed() > t) {
+          stalled.push(*fd);
+        }
+      } else if conn.has_partial_request() {
+        // The read timeouts apply here, not the keep-alive timeout.
+        if !conn.closing && conn.timer.expired() {
+          timed_out.push(*fd);
+        }
+      } else if draining || conn.last_active.elapsed() > timeout {
+        idle.push(*fd);
+      }
+    }
+
+    for fd in idle {
+      debug!("Closing idle connection");
+      self.deregister(fd);
+    }
+    for fd in stalled {
+      info!("Closing connection that stopped reading its response");
+      self.deregister(fd);
+    }
+    for fd in timed_out {
+      info!("Rejecting request: {}", FramingError::TimedOut);
+      if let Some(conn) = self.connections.get_mut(&fd) {
+        conn.reject(FramingError::TimedOut);
+      }
+      self.on_event(fd, EpollFlags::EPOLLOUT);
+    }
+  }
+
+  fn deregister(&mut self, fd: RawFd) {
+    if let Some(conn) = self.connections.remove(&fd) {
+      let _ = epoll_ctl(self.epoll, EpollOp::EpollCtlDel, fd, None);
+      // Dropping the stream closes the socket.
+      drop(conn);
+    }
+  }
+}
+
+impl<'a, L: Accept, H> Drop for Reactor<'a, L, H> {
+  fn drop(&mut self) {
+    let _ = close(self.epoll);
+  }
+}
+
+struct Connection<S: SocketLike> {
+  stream: TcpStream<S>,
+  credentials: Option<PeerCredentials>,
+  read_buf: Vec<u8>,
+  decoder: RequestDecoder,
+  /// Responses waiting to be encoded into `write_buf`, oldest first.
+  responses: VecDeque<BodyEncoder>,
+  write_buf: Vec<u8>,
+  written: usize,
+  served: usize,
+  last_active: Instant,
+  timer: RequestTimer,
+  /// When the pending output last made progress.
+  write_progress: Instant,
+  interest: EpollFlags,
+  /// Set once no more requests will be read, either because the client hung
+  /// up or because the last response asked to close the connection.
+  closing: bool,
+  /// Set once the client closed its end. Requests it sent before that are
+  /// still answered.
+  read_closed: bool,
+  linger: Linger,
+}
+
+/// Whether a rejected connection still discards input before it is closed,
+/// see `server::linger`.
+#[derive(Clone, Copy, PartialEq)]
+enum Linger {
+  No,
+  /// Start once the error response is out.
+  Pending,
+  Until(Instant),
+}
+
+impl<S: SocketLike> Connection<S> {
+  fn new(
+    stream: TcpStream<S>,
+    credentials: Option<PeerCredentials>,
+    config: &ConnectionConfig,
+  ) -> Self {
+    Connection {
+      stream,
+      credentials,
+      read_buf: Vec::new(),
+      decoder: RequestDecoder::new(config.limits.clone()),
+      responses: VecDeque::new(),
+      write_buf: Vec::new(),
+      written: 0,
+      served: 0,
+      last_active: Instant::now(),
+      timer: RequestTimer::new(config.header_read_timeout, config.body_read_timeout),
+      write_progress: Instant::now(),
+      interest: EpollFlags::EPOLLIN,
+      closing: false,
+      read_closed: false,
+      linger: Linger::No,
+    }
+  }
+
+  fn on_ready<H>(
+    &mut self,
+    flags: EpollFlags,
+    config: &ConnectionConfig,
+    handler: &H,
+  ) -> IoResult<()>
+  where
+    H: Handler,
+  {
+    self.last_active = Instant::now();
+
+    let readable = EpollFlags::EPOLLIN | EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR;
+    if flags.intersects(readable) && !self.closing {
+      if !self.read_closed {
+        self.fill()?;
+      }
+    } else if flags.intersects(readable) && matches!(self.linger, Linger::Until(_)) {
+      self.discard();
+    }
+
+    // Keep going through pipelined requests for as long as their responses
+    // go out straight away.
+    loop {
+      let answered = self.process(config, handler);
+      self.flush()?;
+      if !answered || self.has_pending_write() {
+        break;
+      }
+    }
+    if self.linger == Linger::Pending && !self.has_pending_write() {
+      self.linger = match self.stream.shutdown(Shutdown::Write) {
+        Ok(()) => Linger::Until(Instant::now() + LINGER_TIMEOUT),
+        Err(_) => Linger::No,
+      };
+    }
+    Ok(())
+  }
+
+  /// Throws away input on a lingering connection, and stops lingering once
+  /// the client has closed its end.
+  fn discard(&mut self) {
+    let mut chunk = [0; READ_CHUNK];
+    loop {
+      match self.stream.read(&mut chunk) {
+        Ok(0) => break,
+        Ok(_) => {}
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
+        Err(ref err) if err.kind() == ErrorKind::Interrupted => {}
+        Err(_) => break,
+      }
+    }
+    self.linger = Linger::No;
+  }
+
+  /// Reads everything currently available on the socket.
+  fn fill(&mut self) -> IoResult<()> {
+    let mut chunk = [0; READ_CHUNK];
+    loop {
+      match self.stream.read(&mut chunk) {
+        Ok(0) => {
+          self.read_closed = true;
+          return Ok(());
+        }
+        Ok(read) => self.read_buf.extend_from_slice(&chunk[..read]),
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+        Err(err) => return Err(err),
+      }
+    }
+  }
+
+  /// Answers the next complete request in the read buffer, but only once
+  /// the previous response is out, so a client that sends requests without
+  /// reading the responses cannot make us queue up more of them. Returns
+  /// whether a response was queued.
+  fn process<H>(&mut self, config: &ConnectionConfig, handler: &H) -> bool
+  where
+    H: Handler,
+  {
+    if self.closing || self.has_pending_write() {
+      return false;
+    }
+
+    let answered = match self.decoder.decode(&mut self.read_buf) {
+      Ok(Some(mut request)) => {
+        request.set_peer_credentials(self.credentials);
+        self.served += 1;
+        let (response, keep_alive) = respond(request, self.served, config, handler);
+        self.queue(response.into_encoder());
+        self.closing = !keep_alive;
+        true
+      }
+      // Nothing more will complete the request once the client is gone.
+      Ok(None) if self.read_closed => {
+        self.closing = true;
+        false
+      }
+      Ok(None) => false,
+      Err(err) => {
+        info!("Rejecting request: {}", err);
+        self.reject(err);
+        true
+      }
+    };
+
+    let phase = if self.closing {
+      None
+    } else {
+      self.decoder.phase(&self.read_buf)
+    };
+    self.timer.update(phase, self.served);
+    answered
+  }
+
+  /// Answers with `err` and closes once the answer is out and the client
+  /// had a chance to read it.
+  fn reject(&mut self, err: FramingError) {
+    self.queue(err.response().into_encoder());
+    self.closing = true;
+    self.linger = Linger::Pending;
+  }
+
+  fn queue(&mut self, encoder: BodyEncoder) {
+    if !self.has_pending_write() {
+      self.write_progress = Instant::now();
+    }
+    self.responses.push_back(encoder);
+  }
+
+  /// Writes as much of the pending output as the socket accepts, encoding
+  /// more of the queued responses whenever the buffer runs empty.
+  fn flush(&mut self) -> IoResult<()> {
+    loop {
+      if self.written == self.write_buf.len() {
+        self.write_buf.clear();
+        self.written = 0;
+        if let Some(encoder) = self.responses.front_mut() {
+          if encoder.file_pending() {
+            match encoder.send_file(&self.stream) {
+              Ok(_) => self.write_progress = Instant::now(),
+              Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+              Err(ref err) if err.kind() == ErrorKind::Interrupted => {}
+              Err(err) => return Err(err),
+            }
+            continue;
+          }
+        }
+        self.encode_more()?;
+        if self.write_buf.is_empty() {
+          return Ok(());
+        }
+      }
+
+      match self.stream.write(&self.write_buf[self.written..]) {
+        Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+        Ok(written) => {
+          self.written += written;
+          self.write_progress = Instant::now();
+        }
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+        Err(err) => return Err(err),
+      }
+    }
+  }
+
+  fn encode_more(&mut self) -> IoResult<()> {
+    while self.write_buf.len() < WRITE_BATCH {
+      let encoder = match self.responses.front_mut() {
+        Some(encoder) => encoder,
+        None => return Ok(()),
+      };
+      // Flush what we have, then let `flush` send the file itself.
+      if encoder.file_pending() {
+        return Ok(());
+      }
+      match encoder.next_chunk()? {
+        Some(chunk) => self.write_buf.extend_from_slice(&chunk),
+        None => {
+          self.responses.pop_front();
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn has_partial_request(&self) -> bool {
+    !self.read_buf.is_empty() || self.decoder.in_progress()
+  }
+
+  fn has_pending_write(&self) -> bool {
+    self.written < self.write_buf.len() || !self.responses.is_empty()
+  }
+
+  fn finished(&self) -> bool {
+    self.closing && !self.has_pending_write() && self.linger == Linger::No
+  }
+
+  fn interest(&self) -> EpollFlags {
+    // Nothing more is read while a response is pending, or from a closing
+    // connection unless it is lingering.
+    if matches!(self.linger, Linger::Until(_)) {
+      EpollFlags::EPOLLIN
+    } else if self.closing || self.has_pending_write() {
+      EpollFlags::EPOLLOUT
+    } else {
+      EpollFlags::EPOLLIN
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::net::http::error::default_error_response;
+  use crate::net::http::{Limits, Request, Response};
+  use nix::libc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+
+  /// A listener that always looks readable but never manages to accept,
+  /// like one whose process ran out of file descriptors.
+  struct Exhausted {
+    readable: ShutdownHandle,
+    attempts: AtomicUsize,
+  }
+
+  impl Accept for Exhausted {
+    type Socket = Socket;
+
+    fn accept_connection(
+      &self,
+      _flags: SockFlag,
+    ) -> IoResult<(TcpStream<Socket>, Option<PeerCredentials>)> {
+      self.attempts.fetch_add(1, Ordering::SeqCst);
+      Err(Error::from_raw_os_error(libc::EMFILE))
+    }
+
+    fn poll_accept(&self, _timeout: Duration) -> IoResult<bool> {
+      Ok(true)
+    }
+
+    fn set_nonblocking(&self, _nonblocking: bool) -> IoResult<()> {
+      Ok(())
+    }
+  }
+
+  impl AsRawFd for Exhausted {
+    fn as_raw_fd(&self) -> RawFd {
+      self.readable.event_fd()
+    }
+  }
+
+  #[test]
+  fn backs_off_when_accepting_fails() {
+    let listener = Exhausted {
+      readable: ShutdownHandle::new().unwrap(),
+      attempts: AtomicUsize::new(0),
+    };
+    listener.readable.shutdown();
+    let config = ConnectionConfig {
+      keep_alive_timeout: Duration::from_secs(5),
+      max_requests: 100,
+      shutdown: ShutdownHandle::new().unwrap(),
+      error_mapper: Arc::new(default_error_response),
+      header_read_timeout: None,
+      body_read_timeout: None,
+      write_timeout: None,
+      limits: Arc::new(Limits::default()),
+    };
+    let handler = |_: Request| Ok(Response::builder().into());
+
+    thread::scope(|scope| {
+      let serving = scope.spawn(|| run(&listener, &config, &handler, 1, Duration::from_secs(1)));
+      thread::sleep(Duration::from_millis(350));
+      config.shutdown.shutdown();
+      serving.join().unwrap().unwrap();
+    });
+    // Spinning on the readable listener would take thousands of attempts.
+    assert!(listener.attempts.load(Ordering::SeqCst) <= 10);
+  }
+}
@@ -0,0 +1,691 @@
+use super::chunked::ChunkedDecoder;
+use super::common::{find, ParseError, ParseErrorKind};
+use crate::net::http::{HeaderName, Limits, Request, Response, Status};
+use crate::net::tcp::{SocketLike, TcpStream};
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result as IoResult};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Why a request could not be taken off the connection.
+#[derive(Debug)]
+pub(crate) enum FramingError {
+  /// The connection failed or closed halfway through a request.
+  Io(Error),
+  /// The request line or headers could not be parsed, or are too large.
+  Parse(ParseError),
+  /// The body framing is invalid.
+  Malformed,
+  /// The trailer section of a chunked body exceeds the allowed size.
+  TrailersTooLarge,
+  /// The body exceeds the allowed size.
+  BodyTooLarge,
+  /// The body uses a transfer coding other than `chunked`.
+  UnsupportedTransferCoding,
+  /// The client took too long to send the headers or the body.
+  TimedOut,
+}
+
+impl FramingError {
+  pub fn status(&self) -> Status {
+    match self {
+      FramingError::Io(_) | FramingError::Malformed => Status::BadRequest,
+      FramingError::Parse(err) => err.status(),
+      FramingError::TrailersTooLarge => Status::RequestHeaderFieldsTooLarge,
+      FramingError::BodyTooLarge => Status::RequestEntityTooLarge,
+      FramingError::UnsupportedTransferCoding => Status::NotImplemented,
+      FramingError::TimedOut => Status::RequestTimeout,
+    }
+  }
+
+  /// The response telling the client what went wrong. The connection cannot
+  /// be reused afterwards since we no longer know where the next request
+  /// starts.
+  pub fn response(&self) -> Response {
+    let mut response: Response = Response::builder().status(self.status()).into();
+    response
+      .headers_mut()
+      .insert(HeaderName::CONNECTION, "close");
+    response
+  }
+}
+
+impl fmt::Display for FramingError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      FramingError::Io(err) => write!(f, "{}", err),
+      FramingError::Parse(err) => write!(f, "{}", err),
+      FramingError::Malformed => write!(f, "Malformed request body"),
+      FramingError::TrailersTooLarge => write!(f, "Request trailer section too large"),
+      FramingError::BodyTooLarge => write!(f, "Request body too large"),
+      FramingError::UnsupportedTransferCoding => write!(f, "Unsupported transfer coding"),
+      FramingError::TimedOut => write!(f, "Timed out reading request"),
+    }
+  }
+}
+
+impl From<Error> for FramingError {
+  fn from(err: Error) -> Self {
+    match err.kind() {
+      ErrorKind::TimedOut => FramingError::TimedOut,
+      _ => FramingError::Io(err),
+    }
+  }
+}
+
+enum State {
+  Head(HeadScan),
+  Body {
+    request: Request,
+    length: usize,
+  },
+  Chunked {
+    request: Request,
+    decoder: ChunkedDecoder,
+  },
+}
+
+/// How far the head at the front of the buffer has been scanned, so bytes
+/// that trickle in are only looked at once.
+#[derive(Default)]
+struct HeadScan {
+  /// Start of the first line whose end has not been found yet.
+  line_start: usize,
+  /// Where the search for that line's end stopped.
+  searched: usize,
+  /// Complete lines so far, the request line included.
+  lines: usize,
+  /// Where the header fields start, once the request line is complete.
+  fields_start: usize,
+}
+
+/// How the length of a request body is determined (RFC 7230, 3.3.3).
+enum BodyLength {
+  Fixed(usize),
+  Chunked,
+}
+
+/// Cuts requests out of a byte stream: first everything up to the blank line
+/// ending the header section, then the body, which is either exactly
+/// `Content-Length` bytes or a chunked body running up to its last chunk.
+/// Bytes past the end of a request are left in the buffer for the next one.
+pub(crate) struct RequestDecoder {
+  limits: Arc<Limits>,
+  state: State,
+}
+
+impl RequestDecoder {
+  pub fn new(limits: Arc<Limits>) -> Self {
+    RequestDecoder {
+      limits,
+      state: State::Head(HeadScan::default()),
+    }
+  }
+
+  /// Takes the next complete request off the front of `buf`, or returns
+  /// `None` if more bytes are needed. The head is parsed in place, copying
+  /// only the fields the `Request` keeps, and the body is copied out once.
+  pub fn decode(&mut self, buf: &mut Vec<u8>) -> Result<Option<Request>, FramingError> {
+    if let State::Head(scan) = &mut self.state {
+      let head_end = match head_end(&self.limits, scan, buf)? {
+        Some(head_end) => head_end,
+        None => return Ok(None),
+      };
+
+      let request = Request::parse(&buf[..head_end]).map_err(FramingError::Parse)?;
+      buf.drain(..head_end);
+
+      let max_body_bytes = self.limits.body_bytes(request.url().path());
+      self.state = match body_length(&request)? {
+        BodyLength::Fixed(length) if length > max_body_bytes => {
+          return Err(FramingError::BodyTooLarge)
+        }
+        BodyLength::Fixed(length) => State::Body { request, length },
+        BodyLength::Chunked => State::Chunked {
+          request,
+          decoder: ChunkedDecoder::new(max_body_bytes),
+        },
+      };
+    }
+
+    let complete = match &mut self.state {
+      State::Body { length, .. } => buf.len() >= *length,
+      State::Chunked { decoder, .. } => decoder.decode(buf)?,
+      State::Head(_) => false,
+    };
+    if !complete {
+      return Ok(None);
+    }
+
+    match std::mem::replace(&mut self.state, State::Head(HeadScan::default())) {
+      State::Body {
+        mut request,
+        length,
+      } => {
+        // Copy the body out rather than hand over the whole buffer, which
+        // keeps its capacity for the next request.
+        request.set_body(buf[..length].to_vec());
+        buf.drain(..length);
+        Ok(Some(request))
+      }
+      State::Chunked {
+        mut request,
+        decoder,
+      } => {
+        let (body, trailers) = decoder.finish();
+        request.set_body(body);
+        request.set_trailers(trailers);
+        Ok(Some(request))
+      }
+      State::Head(_) => Ok(None),
+    }
+  }
+
+  /// Whether part of a request has been consumed already.
+  pub fn in_progress(&self) -> bool {
+    !matches!(self.state, State::Head(_))
+  }
+
+  /// Which part of a request is being read, given what is buffered.
+  pub fn phase(&self, buf: &[u8]) -> Option<Phase> {
+    if self.in_progress() {
+      Some(Phase::Body)
+    } else if !buf.is_empty() {
+      Some(Phase::Head)
+    } else {
+      None
+    }
+  }
+}
+
+/// Finds where the head at the front of `buf` ends, checking the request
+/// line and the header section against the limits as soon as enough of them
+/// has arrived to tell. Scanning resumes where `scan` left off.
+fn head_end(
+  limits: &Limits,
+  scan: &mut HeadScan,
+  buf: &[u8],
+) -> Result<Option<usize>, FramingError> {
+  let too_large = |kind, offset| FramingError::Parse(ParseError::new(kind, offset));
+  let max_line = limits.request_line();
+  let max_fields = limits.header_bytes();
+
+  loop {
+    // A `\r` at the end of the last search may be completed by a `\n` now.
+    let from = scan.searched.saturating_sub(1).max(scan.line_start);
+    let end = match find(&buf[from..], b"\r\n") {
+      Some(pos) => from + pos,
+      None => {
+        scan.searched = buf.len();
+        if scan.lines == 0 && buf.len() > max_line + 1 {
+          return Err(too_large(ParseErrorKind::UriTooLong, max_line));
+        }
+        if scan.lines > 0 && buf.len() - scan.fields_start >= max_fields + 2 {
+          return Err(too_large(
+            ParseErrorKind::HeadersTooLarge,
+            scan.fields_start + max_fields,
+          ));
+        }
+        return Ok(None);
+      }
+    };
+
+    if scan.lines == 0 {
+      if end > max_line {
+        return Err(too_large(ParseErrorKind::UriTooLong, max_line));
+      }
+      scan.fields_start = end + 2;
+    } else if end - scan.fields_start > max_fields {
+      return Err(too_large(
+        ParseErrorKind::HeadersTooLarge,
+        scan.fields_start + max_fields,
+      ));
+    } else if end == scan.line_start {
+      // The blank line ending the head.
+      return Ok(Some(end + 2));
+    } else if scan.lines > limits.headers() {
+      return Err(too_large(ParseErrorKind::HeadersTooLarge, scan.line_start));
+    }
+
+    scan.lines += 1;
+    scan.line_start = end + 2;
+    scan.searched = scan.line_start;
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Phase {
+  Head,
+  Body,
+}
+
+/// Enforces separate deadlines for receiving the head and the body of a
+/// request. Each phase's clock starts when it is first reported.
+pub(crate) struct RequestTimer {
+  head_timeout: Option<Duration>,
+  body_timeout: Option<Duration>,
+  /// The current phase, the request it belongs to and when it started.
+  current: Option<(Phase, usize, Instant)>,
+}
+
+impl RequestTimer {
+  pub fn new(head_timeout: Option<Duration>, body_timeout: Option<Duration>) -> Self {
+    RequestTimer {
+      head_timeout,
+      body_timeout,
+      current: None,
+    }
+  }
+
+  /// Records that `request` (a running count) is in `phase`, or that no
+  /// request is being read when `None`.
+  pub fn update(&mut self, phase: Option<Phase>, request: usize) {
+    self.current = match (phase, self.current) {
+      (Some(phase), Some((current, current_request, started)))
+        if phase == current && request == current_request =>
+      {
+        Some((current, current_request, started))
+      }
+      (Some(phase), _) => Some((phase, request, Instant::now())),
+      (None, _) => None,
+    };
+  }
+
+  /// How much time the current phase has left, if it is limited.
+  pub fn remaining(&self) -> Option<Duration> {
+    let (phase, _, started) = self.current?;
+    let timeout = match phase {
+      Phase::Head => self.head_timeout?,
+      Phase::Body => self.body_timeout?,
+    };
+    Some(timeout.saturating_sub(started.elapsed()))
+  }
+
+  pub fn expired(&self) -> bool {
+    self.remaining() == Some(Duration::from_secs(0))
+  }
+}
+
+/// A stream whose reads can be bounded in time.
+pub(crate) trait TimedRead: Read {
+  fn set_read_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()>;
+}
+
+impl<S: SocketLike> TimedRead for &TcpStream<S> {
+  fn set_read_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()> {
+    TcpStream::set_read_timeout(self, timeout)
+  }
+}
+
+/// Reads whole requests off a blocking stream with a `RequestDecoder`.
+pub(crate) struct RequestReader {
+  buf: Vec<u8>,
+  decoder: RequestDecoder,
+  timer: RequestTimer,
+  requests: usize,
+}
+
+impl RequestReader {
+  pub fn new(limits: Arc<Limits>) -> Self {
+    RequestReader {
+      buf: Vec::new(),
+      decoder: RequestDecoder::new(limits),
+      timer: RequestTimer::new(None, None),
+      requests: 0,
+    }
+  }
+
+  /// Limits how long reading the head and the body of a request may take.
+  /// Exceeding either fails with `FramingError::TimedOut`.
+  pub fn timeouts(mut self, head: Option<Duration>, body: Option<Duration>) -> Self {
+    self.timer = RequestTimer::new(head, body);
+    self
+  }
+
+  /// Starts the head deadline of the next request now, before its first
+  /// byte arrives, and returns how long it allows. Used while waiting for
+  /// a new connection's first request, which the head timeout also covers.
+  pub fn start_head(&mut self) -> Option<Duration> {
+    self.timer.update(Some(Phase::Head), self.requests);
+    self.timer.remaining()
+  }
+
+  /// Whether bytes of the next request have already been read, in which case
+  /// there is no point in waiting for the socket to become readable.
+  pub fn has_buffered(&self) -> bool {
+    !self.buf.is_empty() || self.decoder.in_progress()
+  }
+
+  /// Reads until a whole request has arrived. Returns `None` if the peer
+  /// closed the connection cleanly between requests.
+  pub fn read_request<R: TimedRead>(
+    &mut self,
+    mut stream: R,
+  ) -> Result<Option<Request>, FramingError> {
+    let mut chunk = [0; READ_CHUNK];
+    loop {
+      if let Some(request) = self.decoder.decode(&mut self.buf)? {
+        self.requests += 1;
+        self.timer.update(None, self.requests);
+        return Ok(Some(request));
+      }
+
+      // We are only asked to read once a request is arriving, so even with
+      // nothing buffered yet we are reading its head.
+      let phase = self.decoder.phase(&self.buf).unwrap_or(Phase::Head);
+      self.timer.update(Some(phase), self.requests);
+      let remaining = self.timer.remaining();
+      if remaining == Some(Duration::from_secs(0)) {
+        return Err(FramingError::TimedOut);
+      }
+      // Always set it, so a phase without a limit does not inherit the
+      // timeout left over from the previous one.
+      stream.set_read_timeout(remaining)?;
+
+      let read = match stream.read(&mut chunk) {
+        Ok(read) => read,
+        Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+        Err(err) => return Err(FramingError::from(err)),
+      };
+
+      if read == 0 {
+        return if self.has_buffered() {
+          Err(FramingError::Io(Error::from(ErrorKind::UnexpectedEof)))
+        } else {
+          Ok(None)
+        };
+      }
+      self.buf.extend_from_slice(&chunk[..read]);
+    }
+  }
+}
+
+fn body_length(request: &Request) -> Result<BodyLength, FramingError> {
+  let headers = request.headers();
+  // Repeated fields are one comma separated list, except that every
+  // Content-Length must agree or the message has no trustworthy length.
+  let codings: Vec<&str> = headers.get_all(HeaderName::TRANSFER_ENCODING).collect();
+  let codings = Some(codings.join(",")).filter(|_| !codings.is_empty());
+  let mut lengths = headers.get_all(HeaderName::CONTENT_LENGTH).map(str::trim);
+  let length = lengths.next();
+  if lengths.any(|other| Some(other) != length) {
+    return Err(FramingError::Malformed);
+  }
+  match (codings.as_deref(), length) {
+    // A message with both is a classic request smuggling vector, refuse it
+    // rather than guess which one an upstream proxy honoured.
+    (Some(_), Some(_)) => Err(FramingError::Malformed),
+    (Some(codings), None) => {
+      let mut codings = codings.split(',').map(str::trim);
+      match codings.next_back() {
+        Some(last) if last.eq_ignore_ascii_case("chunked") => {}
+        _ => return Err(FramingError::Malformed),
+      }
+      if codings.next().is_some() {
+        return Err(FramingError::UnsupportedTransferCoding);
+      }
+      Ok(BodyLength::Chunked)
+    }
+    (None, Some(value)) => {
+      let value = value.trim();
+      if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(FramingError::Malformed);
+      }
+      // Anything that does not even fit a usize is certainly too large.
+      value
+        .parse()
+        .map(BodyLength::Fixed)
+        .map_err(|_| FramingError::BodyTooLarge)
+    }
+    (None, None) => Ok(BodyLength::Fixed(0)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limits() -> Limits {
+    Limits::default()
+      .max_request_line(256)
+      .max_header_bytes(1024)
+      .max_body_bytes(1024)
+  }
+
+  fn read_all(chunks: &[&[u8]]) -> Result<Option<Request>, FramingError> {
+    read_with(limits(), chunks)
+  }
+
+  fn read_with(limits: Limits, chunks: &[&[u8]]) -> Result<Option<Request>, FramingError> {
+    struct Chunks<'a>(std::slice::Iter<'a, &'a [u8]>);
+
+    impl<'a> TimedRead for Chunks<'a> {
+      fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> IoResult<()> {
+        Ok(())
+      }
+    }
+
+    impl<'a> Read for Chunks<'a> {
+      fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match self.0.next() {
+          Some(chunk) => {
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+          }
+          None => Ok(0),
+        }
+      }
+    }
+
+    RequestReader::new(Arc::new(limits)).read_request(Chunks(chunks.iter()))
+  }
+
+  #[test]
+  fn reads_request_split_across_reads() {
+    let request = read_all(&[
+      b"POST /upload HTTP/1.1\r\nHo",
+      b"st: localhost\r\nContent-Length: 11\r\n",
+      b"\r\nhello",
+      b" world",
+    ])
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(request.url().path(), "/upload");
+    assert_eq!(request.body_bytes(), b"hello world");
+  }
+
+  #[test]
+  fn keeps_binary_bodies_intact() {
+    let request = read_all(&[
+      b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\n\x1f\x8b",
+      b"\x08\xff",
+    ])
+    .unwrap()
+    .unwrap();
+    assert_eq!(request.body_bytes(), b"\x1f\x8b\x08\xff");
+
+    let chunked = read_all(&[
+      b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n\xc3\x28\r\n0\r\n\r\n",
+    ]);
+    assert_eq!(chunked.unwrap().unwrap().body_bytes(), b"\xc3\x28");
+  }
+
+  #[test]
+  fn leaves_pipelined_requests_in_the_buffer() {
+    let mut decoder = RequestDecoder::new(Arc::new(limits()));
+    let mut buf = b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET / HTTP/1.1\r\n".to_vec();
+
+    let capacity = buf.capacity();
+
+    let request = decoder.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(request.body_bytes(), b"hi");
+    assert_eq!(buf, b"GET / HTTP/1.1\r\n");
+    assert_eq!(buf.capacity(), capacity);
+    assert!(decoder.decode(&mut buf).unwrap().is_none());
+  }
+
+  #[test]
+  fn clean_close_between_requests() {
+    assert!(read_all(&[]).unwrap().is_none());
+  }
+
+  #[test]
+  fn close_mid_request_is_an_error() {
+    let result = read_all(&[b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhi"]);
+    assert!(matches!(result, Err(FramingError::Io(_))));
+  }
+
+  #[test]
+  fn rejects_oversized_requests() {
+    let body = read_all(&[b"POST / HTTP/1.1\r\nContent-Length: 4096\r\n\r\n"]);
+    assert_eq!(body.unwrap_err().status().code(), 413);
+
+    let target = [b'a'; 2048];
+    assert_eq!(read_all(&[&target]).unwrap_err().status().code(), 414);
+
+    let headers = [b"GET / HTTP/1.1\r\nX-Filler: ".as_slice(), &[b'a'; 2048]];
+    assert_eq!(read_all(&headers).unwrap_err().status().code(), 431);
+  }
+
+  #[test]
+  fn enforces_each_limit_at_its_boundary() {
+    let code = |limits: Limits, raw: &[u8]| match read_with(limits, &[raw]) {
+      Ok(_) => 200,
+      Err(err) => err.status().code(),
+    };
+
+    let line = Limits::default().max_request_line(16);
+    assert_eq!(code(line.clone(), b"GET /0123456789 HTTP/1.1\r\n\r\n"), 414);
+    assert_eq!(code(line.clone(), b"GET / HTTP/1.1\r\n\r\n"), 200);
+    assert_eq!(code(line, b"GET /0123456789abc"), 414);
+
+    let fields = Limits::default().max_header_bytes(8);
+    assert_eq!(
+      code(fields.clone(), b"GET / HTTP/1.1\r\nA: 123\r\n\r\n"),
+      200
+    );
+    assert_eq!(code(fields, b"GET / HTTP/1.1\r\nA: 1234\r\n\r\n"), 431);
+
+    let count = Limits::default().max_headers(2);
+    assert_eq!(
+      code(count.clone(), b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n"),
+      200
+    );
+    let err = read_with(count, &[b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"]);
+    match err {
+      Err(FramingError::Parse(err)) => {
+        assert_eq!(err.kind(), ParseErrorKind::HeadersTooLarge);
+        assert_eq!(err.offset(), 28);
+      }
+      other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn resumes_scanning_the_head_across_reads() {
+    let bytes = |raw: &'static [u8]| raw.chunks(1).collect::<Vec<_>>();
+    let raw = b"GET / HTTP/1.1\r\nHost: a\r\nAccept: */*\r\n\r\n";
+    let request = read_with(Limits::default(), &bytes(raw)).unwrap().unwrap();
+    assert_eq!(request.headers().get("accept"), Some("*/*"));
+
+    let count = Limits::default().max_headers(2);
+    let raw = b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+    match read_with(count, &bytes(raw)) {
+      Err(FramingError::Parse(err)) => assert_eq!(err.offset(), 28),
+      other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+    }
+
+    let fields = Limits::default().max_header_bytes(8);
+    let raw = b"GET / HTTP/1.1\r\nA: 1234\r\n\r\n";
+    let err = read_with(fields, &bytes(raw)).unwrap_err();
+    assert_eq!(err.status().code(), 431);
+  }
+
+  #[test]
+  fn limits_bodies_per_route() {
+    let limits = Limits::default()
+      .max_body_bytes(4)
+      .max_body_bytes_for("/upload/*file", 8);
+    let post = |path: &str, body: &str| {
+      let raw = format!(
+        "POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+      );
+      match read_with(limits.clone(), &[raw.as_bytes()]) {
+        Ok(_) => 200,
+        Err(err) => err.status().code(),
+      }
+    };
+
+    assert_eq!(post("/upload/cat.png", "12345678"), 200);
+    assert_eq!(post("/upload/cat.png", "123456789"), 413);
+    assert_eq!(post("/comments", "1234"), 200);
+    assert_eq!(post("/comments", "12345"), 413);
+
+    let chunked =
+      b"POST /comments HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n12345\r\n0\r\n\r\n";
+    assert_eq!(
+      read_with(limits, &[chunked]).unwrap_err().status().code(),
+      413
+    );
+  }
+
+  #[test]
+  fn reads_chunked_body() {
+    let request = read_all(&[
+      b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel",
+      b"lo\r\n6;ext\r\n world\r\n0\r\nDigest: abc\r\n\r\n",
+    ])
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(request.body_bytes(), b"hello world");
+    assert_eq!(request.trailers().get("digest"), Some("abc"));
+  }
+
+  #[test]
+  fn rejects_ambiguous_or_unsupported_framing() {
+    let both =
+      read_all(&[b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n"]);
+    assert_eq!(both.unwrap_err().status().code(), 400);
+
+    let gzip = read_all(&[b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"]);
+    assert_eq!(gzip.unwrap_err().status().code(), 501);
+
+    let chunked = read_all(&[b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"]);
+    assert!(matches!(chunked, Err(FramingError::Io(_))));
+  }
+
+  #[test]
+  fn rejects_invalid_content_length() {
+    let result = read_all(&[b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"]);
+    assert_eq!(result.unwrap_err().status().code(), 400);
+
+    let conflicting =
+      read_all(&[b"POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 3\r\n\r\nhi"]);
+    assert_eq!(conflicting.unwrap_err().status().code(), 400);
+
+    let repeated =
+      read_all(&[b"POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 2\r\n\r\nhi"]);
+    assert_eq!(repeated.unwrap().unwrap().body_bytes(), b"hi");
+  }
+
+  #[test]
+  fn times_out_each_phase_separately() {
+    let mut timer = RequestTimer::new(Some(Duration::from_secs(60)), Some(Duration::from_secs(0)));
+    assert_eq!(timer.remaining(), None);
+
+    timer.update(Some(Phase::Head), 0);
+    assert!(!timer.expired());
+    timer.update(Some(Phase::Body), 0);
+    assert!(timer.expired());
+
+    timer.update(None, 1);
+    assert!(!timer.expired());
+  }
+}
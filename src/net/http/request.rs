@@ -1,28 +1,59 @@
 use super::common::*;
+use super::headers::{HeaderName, Headers};
+use super::router::Params;
+use crate::net::unix::PeerCredentials;
 use std::io::{Error, ErrorKind, Result as IoResult};
-use std::str::FromStr;
+use std::str::{FromStr, Utf8Error};
 
+/// A parsed request. It owns its fields and body rather than borrowing
+/// them from the connection's read buffer: handlers take the request by
+/// value and may keep it past the next read, which a borrowed request would
+/// not allow without a lifetime on `Handler`. Parsing works on the buffer
+/// in place and copies each field and the body once.
 #[derive(Default, Debug)]
 pub struct Request {
   method: Method,
   url: Url,
   version: Version,
   headers: Headers,
-  body: String,
+  body: Vec<u8>,
+  trailers: Headers,
+  params: Params,
+  peer_credentials: Option<PeerCredentials>,
 }
 
 impl Request {
-  pub fn parse(raw: &str) -> Result<Self, ParseError> {
-    let (rest, method) = Method::parse(raw)?;
-    let (rest, url) = Url::parse(rest)?;
-    let (rest, version) = Version::parse(rest)?;
-    let (body, headers) = Headers::parse(rest)?;
+  /// Parses a request head, and anything after it as the body. The
+  /// request line must be printable ASCII, and header names tokens.
+  pub fn parse(raw: &[u8]) -> Result<Self, ParseError> {
+    let line_end =
+      find(raw, b"\r\n").ok_or_else(|| ParseError::new(ParseErrorKind::Incomplete, raw.len()))?;
+    if let Some(bad) = raw[..line_end]
+      .iter()
+      .position(|b| !(b' '..=b'~').contains(b))
+    {
+      // Blame whichever part of the request line the byte falls in.
+      let kind = match raw[..bad].iter().filter(|&&b| b == b' ').count() {
+        0 => ParseErrorKind::InvalidMethod,
+        1 => ParseErrorKind::InvalidTarget,
+        _ => ParseErrorKind::InvalidVersion,
+      };
+      return Err(ParseError::new(kind, bad));
+    }
+    let (line, rest) = raw.split_at(line_end + 2);
+    let line = std::str::from_utf8(line).expect("the request line is ASCII");
+
+    let offset = |rest: &str| line.len() - rest.len();
+    let (after_method, method) = Method::parse(line)?;
+    let (after_url, url) = Url::parse(after_method).map_err(|err| err.at(offset(after_method)))?;
+    let (_, version) = Version::parse(after_url).map_err(|err| err.at(offset(after_url)))?;
+    let (body, headers) = Headers::parse_bytes(rest).map_err(|err| err.at(line.len()))?;
     let req: Request = Request::builder()
       .method(method)
       .url(url)
       .version(version)
       .headers(headers)
-      .body(body.to_string())
+      .body(body)
       .into();
     Ok(req)
   }
@@ -47,9 +78,69 @@ impl Request {
     &self.version
   }
 
-  pub fn body(&self) -> &str {
+  pub fn body_bytes(&self) -> &[u8] {
     &self.body
   }
+
+  /// The body as text, failing if it is not valid UTF-8.
+  pub fn body_str(&self) -> Result<&str, Utf8Error> {
+    std::str::from_utf8(&self.body)
+  }
+
+  pub(crate) fn set_body(&mut self, body: Vec<u8>) {
+    self.body = body;
+  }
+
+  /// Trailer fields sent after a chunked body.
+  pub fn trailers(&self) -> &Headers {
+    &self.trailers
+  }
+
+  pub(crate) fn set_trailers(&mut self, trailers: Headers) {
+    self.trailers = trailers;
+  }
+
+  /// Path parameters captured by the `Router` that dispatched this request.
+  pub fn params(&self) -> &Params {
+    &self.params
+  }
+
+  /// Shorthand for `params().get(name)`.
+  pub fn param(&self, name: &str) -> Option<&str> {
+    self.params.get(name)
+  }
+
+  pub(crate) fn set_params(&mut self, params: Params) {
+    self.params = params;
+  }
+
+  /// The credentials of the client process, for requests received over a
+  /// Unix socket.
+  pub fn peer_credentials(&self) -> Option<&PeerCredentials> {
+    self.peer_credentials.as_ref()
+  }
+
+  pub(crate) fn set_peer_credentials(&mut self, credentials: Option<PeerCredentials>) {
+    self.peer_credentials = credentials;
+  }
+
+  /// Whether the client wants the connection kept open after this request.
+  /// HTTP/1.1 connections are persistent unless the client sends
+  /// `Connection: close`; HTTP/1.0 ones only if it asks for `keep-alive`.
+  pub fn keep_alive(&self) -> bool {
+    let connection = self.headers.get(HeaderName::CONNECTION).unwrap_or("");
+    let has_token = |token: &str| {
+      connection
+        .split(',')
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+    };
+
+    if self.version >= Version::new(1, 1) {
+      !has_token("close")
+    } else {
+      has_token("keep-alive")
+    }
+  }
 }
 
 impl From<RequestBuilder> for Request {
@@ -81,13 +172,14 @@ impl RequestBuilder {
     self
   }
 
-  pub fn body(mut self, body: String) -> Self {
-    self.0.body = body;
+  pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+    self.0.body = body.into();
     self
   }
 }
 
-#[derive(Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Method {
   OPTIONS,
   GET,
@@ -123,22 +215,41 @@ impl FromStr for Method {
   }
 }
 
+impl Method {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Method::OPTIONS => "OPTIONS",
+      Method::GET => "GET",
+      Method::HEAD => "HEAD",
+      Method::POST => "POST",
+      Method::PUT => "PUT",
+      Method::DELETE => "DELETE",
+      Method::TRACE => "TRACE",
+      Method::CONNECT => "CONNECT",
+    }
+  }
+}
+
+#[allow(clippy::derivable_impls)]
 impl Default for Method {
   fn default() -> Self {
-    return Method::GET;
+    Method::GET
   }
 }
 
 impl Parse for Method {
   fn parse(txt: &str) -> Result<(&str, Self), ParseError> {
-    let mut itr = txt.splitn(2, " ");
-
-    let method = itr.next().ok_or(ParseError())?;
-    let rest = itr.next().ok_or(ParseError())?;
+    let invalid = |kind| ParseError::new(kind, 0);
+    let (method, rest) = txt
+      .split_once(' ')
+      .ok_or_else(|| invalid(ParseErrorKind::InvalidMethod))?;
 
     match method.parse() {
       Ok(meth) => Ok((rest, meth)),
-      _ => Err(ParseError()),
+      Err(_) if !method.is_empty() && method.bytes().all(is_token) => {
+        Err(invalid(ParseErrorKind::UnknownMethod))
+      }
+      Err(_) => Err(invalid(ParseErrorKind::InvalidMethod)),
     }
   }
 }
@@ -159,12 +270,12 @@ mod tests {
 
   #[test]
   fn good_parse() {
-    assert!(Request::parse(SAMPLE_REQUEST).is_ok());
+    assert!(Request::parse(SAMPLE_REQUEST.as_bytes()).is_ok());
   }
 
   #[test]
   fn bad_parse() {
-    assert!(Request::parse(BAD_REQUEST).is_err());
+    assert!(Request::parse(BAD_REQUEST.as_bytes()).is_err());
   }
 
   #[test]
@@ -188,6 +299,75 @@ mod tests {
   #[test]
   fn parse_headers() {
     let parsed = Headers::parse(SAMPLE_REQUEST_HEADERS);
-    assert!(parsed.is_ok() && parsed.unwrap().0 == "");
+    assert!(parsed.is_ok() && parsed.unwrap().0.is_empty());
+  }
+
+  #[test]
+  fn keep_alive_by_version() {
+    let close = "GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+    let legacy = "GET / HTTP/1.0\r\nHost: localhost\r\n\r\n";
+    let legacy_keep_alive = "GET / HTTP/1.0\r\nconnection: Keep-Alive\r\n\r\n";
+
+    assert!(Request::parse(SAMPLE_REQUEST.as_bytes())
+      .unwrap()
+      .keep_alive());
+    assert!(!Request::parse(close.as_bytes()).unwrap().keep_alive());
+    assert!(!Request::parse(legacy.as_bytes()).unwrap().keep_alive());
+    assert!(Request::parse(legacy_keep_alive.as_bytes())
+      .unwrap()
+      .keep_alive());
+  }
+
+  #[test]
+  fn keeps_binary_bodies() {
+    let mut raw = b"POST /upload HTTP/1.1\r\nContent-Type: image/png\r\n\r\n".to_vec();
+    raw.extend_from_slice(&[0x89, b'P', b'N', b'G', 0xff, 0x00]);
+    let request = Request::parse(&raw).unwrap();
+    assert_eq!(request.body_bytes(), &[0x89, b'P', b'N', b'G', 0xff, 0x00]);
+    assert!(request.body_str().is_err());
+
+    let text = Request::parse(b"POST / HTTP/1.1\r\n\r\nhello").unwrap();
+    assert_eq!(text.body_str(), Ok("hello"));
+  }
+
+  #[test]
+  fn rejects_non_ascii_heads() {
+    assert!(Request::parse(b"GET /caf\xc3\xa9 HTTP/1.1\r\n\r\n").is_err());
+    assert!(Request::parse(b"GET /\x00 HTTP/1.1\r\n\r\n").is_err());
+    assert!(Request::parse(b"GET / HTTP/1.1\r\nX-Caf\xc3\xa9: 1\r\n\r\n").is_err());
+    assert!(Request::parse(b"GET / HTTP/1.1 extra\r\n\r\n").is_err());
+
+    let request =
+      Request::parse(b"GET /caf%C3%A9 HTTP/1.1\r\nX-Name: caf\xc3\xa9\r\n\r\n").unwrap();
+    assert_eq!(request.url().path(), "/café");
+    assert_eq!(request.headers().get("x-name"), Some("café"));
+  }
+
+  #[test]
+  fn reports_what_failed_and_where() {
+    let error = |raw: &str| {
+      let err = Request::parse(raw.as_bytes()).unwrap_err();
+      (err.kind(), err.offset(), err.status().code())
+    };
+    use ParseErrorKind::*;
+
+    assert_eq!(error("G@T / HTTP/1.1\r\n\r\n"), (InvalidMethod, 0, 400));
+    assert_eq!(error("BREW / HTTP/1.1\r\n\r\n"), (UnknownMethod, 0, 501));
+    assert_eq!(error("GET /a%zz HTTP/1.1\r\n\r\n"), (InvalidTarget, 6, 400));
+    assert_eq!(error("GET /\tx HTTP/1.1\r\n\r\n"), (InvalidTarget, 5, 400));
+    assert_eq!(error("GET / HTTP/1.1 x\r\n\r\n"), (InvalidVersion, 6, 400));
+    assert_eq!(
+      error("GET / HTTP/3.0\r\n\r\n"),
+      (UnsupportedVersion, 6, 505)
+    );
+    assert_eq!(
+      error("GET / HTTP/1.1\r\nHost: a\r\nX y: 1\r\n\r\n"),
+      (InvalidHeader, 26, 400)
+    );
+    assert_eq!(
+      error("GET / HTTP/1.1\r\nHost: a\r\n"),
+      (Incomplete, 25, 400)
+    );
+    assert_eq!(error("GET / HTTP/1.1"), (Incomplete, 14, 400));
   }
 }
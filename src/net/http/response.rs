@@ -1,5 +1,8 @@
+use super::body::{Body, BodyEncoder};
 use super::common::*;
+use super::headers::{HeaderName, Headers};
 use std::fmt;
+use std::io::{Result as IoResult, Write};
 
 #[derive(Default, Debug)]
 pub struct Response {
@@ -7,7 +10,10 @@ pub struct Response {
   version: Version,
   url: Url,
   headers: Headers,
-  body: Vec<u8>,
+  body: Body,
+  /// Set once the body was dropped, so the headers still describe the one
+  /// that would have been sent.
+  body_omitted: bool,
 }
 
 impl Response {
@@ -31,15 +37,56 @@ impl Response {
     &self.headers
   }
 
-  pub fn body(&self) -> &[u8] {
+  pub fn headers_mut(&mut self) -> &mut Headers {
+    &mut self.headers
+  }
+
+  pub fn body(&self) -> &Body {
     &self.body
   }
 
-  pub fn as_bytes(&self) -> Vec<u8> {
-    let mut bytes = Vec::from(self.to_string().as_bytes());
-    let mut body_bytes = Vec::from(self.body());
-    bytes.append(&mut body_bytes);
-    bytes
+  /// Whether the body is sent with `Transfer-Encoding: chunked`.
+  pub fn is_chunked(&self) -> bool {
+    self
+      .headers
+      .get(HeaderName::TRANSFER_ENCODING)
+      .map(|codings| codings.to_ascii_lowercase().contains("chunked"))
+      .unwrap_or(false)
+  }
+
+  /// Writes the status line and headers followed by the body, which is
+  /// streamed piece by piece rather than buffered.
+  pub fn write_to<W: Write>(self, writer: &mut W) -> IoResult<()> {
+    self.into_encoder().write_to(writer)
+  }
+
+  /// Whether the status rules out a body (RFC 7230, 3.3.3).
+  pub(crate) fn has_bodiless_status(&self) -> bool {
+    let code = self.status.code();
+    code < 200 || code == 204 || code == 304
+  }
+
+  /// Drops the body but keeps the headers that describe it, including its
+  /// length, as the answer to a `HEAD` request needs.
+  pub(crate) fn omit_body(&mut self) {
+    if !self.headers.contains(HeaderName::CONTENT_LENGTH)
+      && !self.is_chunked()
+      && !self.has_bodiless_status()
+    {
+      if let Some(length) = self.body.len() {
+        self
+          .headers
+          .insert(HeaderName::CONTENT_LENGTH, length.to_string());
+      }
+    }
+    self.body = Body::empty();
+    self.body_omitted = true;
+  }
+
+  pub(crate) fn into_encoder(self) -> BodyEncoder {
+    let head = self.to_string().into_bytes();
+    let chunked = self.is_chunked() && !self.body_omitted;
+    BodyEncoder::new(head, self.body, chunked)
   }
 }
 
@@ -51,11 +98,22 @@ impl From<ResponseBuilder> for Response {
 
 impl fmt::Display for Response {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    let mut result = format!("{} {}\n", self.version(), self.status());
-    for (header, field) in self.headers().map.iter() {
-      result = format!("{}{}: {}\n", result, header, field);
+    let mut result = format!("{} {}\r\n", self.version(), self.status());
+    for (header, field) in self.headers().iter() {
+      result = format!("{}{}: {}\r\n", result, header, field);
     }
-    result = format!("{}\n", result);
+    // Without a length the client can only find the end of the body by
+    // waiting for us to close the connection, which defeats keep-alive.
+    // Responses that never have a body go without (RFC 7230, 3.3.2).
+    if self.headers().get(HeaderName::CONTENT_LENGTH).is_none()
+      && !self.is_chunked()
+      && !self.has_bodiless_status()
+    {
+      if let Some(length) = self.body.len() {
+        result = format!("{}Content-Length: {}\r\n", result, length);
+      }
+    }
+    result = format!("{}\r\n", result);
     write!(f, "{}", result)
   }
 }
@@ -78,8 +136,13 @@ impl ResponseBuilder {
     self
   }
 
-  pub fn header(mut self, (key, value): (String, String)) -> Self {
-    self.0.headers.map.insert(key, value);
+  /// Adds a header after any others with the same name.
+  pub fn header<N, V>(mut self, (name, value): (N, V)) -> Self
+  where
+    N: Into<HeaderName>,
+    V: Into<String>,
+  {
+    self.0.headers.append(name, value);
     self
   }
 
@@ -88,13 +151,13 @@ impl ResponseBuilder {
     self
   }
 
-  pub fn body(mut self, body: Vec<u8>) -> Self {
-    self.0.body = body;
+  pub fn body(mut self, body: impl Into<Body>) -> Self {
+    self.0.body = body.into();
     self
   }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
   Continue = 100,           // RFC 7231, 6.2.1
   SwitchingProtocols = 101, // RFC 7231, 6.2.2
@@ -165,13 +228,14 @@ pub enum Status {
   NetworkAuthenticationRequired = 511, // RFC 6585, 6
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for Status {
   fn default() -> Self {
     Status::OK
   }
 }
 
-impl<'a> fmt::Display for Status {
+impl fmt::Display for Status {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     write!(f, "{} {}", self.code(), self.text())
   }
@@ -322,3 +386,17 @@ impl Status {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sends_length_only_where_a_body_may_follow() {
+    let head = |status| Response::builder().status(status).body("").0.to_string();
+    assert!(head(Status::OK).contains("Content-Length: 0\r\n"));
+    assert!(!head(Status::Continue).contains("Content-Length"));
+    assert!(!head(Status::NoContent).contains("Content-Length"));
+    assert!(!head(Status::NotModified).contains("Content-Length"));
+  }
+}
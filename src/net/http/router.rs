@@ -0,0 +1,273 @@
+use crate::net::http::{Handler, HeaderName, Method, Request, Response, Status};
+use std::io::Result as IoResult;
+
+/// Path parameters captured while routing a request, in pattern order.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Params {
+  list: Vec<(String, String)>,
+}
+
+impl Params {
+  pub fn get(&self, name: &str) -> Option<&str> {
+    self
+      .list
+      .iter()
+      .find(|(key, _)| key == name)
+      .map(|(_, value)| value.as_str())
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+    self
+      .list
+      .iter()
+      .map(|(key, value)| (key.as_str(), value.as_str()))
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.list.is_empty()
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+  Literal(String),
+  /// `:name`, matches exactly one non-empty segment.
+  Param(String),
+  /// `*name`, matches the rest of the path. Only allowed last.
+  Wildcard(String),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern {
+  segments: Vec<Segment>,
+}
+
+impl Pattern {
+  pub(crate) fn parse(pattern: &str) -> Self {
+    assert!(
+      pattern.starts_with('/'),
+      "route pattern must start with '/': {}",
+      pattern
+    );
+
+    let parts: Vec<&str> = pattern[1..].split('/').collect();
+    let segments = parts
+      .iter()
+      .enumerate()
+      .map(|(i, part)| {
+        if let Some(name) = part.strip_prefix(':') {
+          Segment::Param(name.to_string())
+        } else if let Some(name) = part.strip_prefix('*') {
+          assert!(
+            i == parts.len() - 1,
+            "wildcard must be the last segment: {}",
+            pattern
+          );
+          Segment::Wildcard(name.to_string())
+        } else {
+          Segment::Literal(part.to_string())
+        }
+      })
+      .collect();
+
+    Pattern { segments }
+  }
+
+  pub(crate) fn matches(&self, path: &str) -> Option<Params> {
+    let path = path.strip_prefix('/')?;
+    let mut parts = path.split('/');
+    let mut params = Params::default();
+
+    for segment in &self.segments {
+      match segment {
+        Segment::Wildcard(name) => {
+          let rest = parts.collect::<Vec<_>>().join("/");
+          params.list.push((name.clone(), rest));
+          return Some(params);
+        }
+        Segment::Literal(literal) => {
+          if parts.next()? != literal {
+            return None;
+          }
+        }
+        Segment::Param(name) => match parts.next()? {
+          "" => return None,
+          value => params.list.push((name.clone(), value.to_string())),
+        },
+      }
+    }
+
+    match parts.next() {
+      Some(_) => None,
+      None => Some(params),
+    }
+  }
+}
+
+struct Route {
+  method: Method,
+  pattern: Pattern,
+  handler: Box<dyn Handler>,
+}
+
+/// Dispatches requests to handlers by method and path. Patterns are literal
+/// segments, `:name` parameters matching a single segment and an optional
+/// trailing `*name` wildcard matching the rest of the path:
+///
+/// ```ignore
+/// Router::new()
+///   .get("/users/:id", show_user)
+///   .get("/static/*path", serve_file);
+/// ```
+///
+/// Routes are tried in the order they were added. If no pattern matches the
+/// router answers `404 Not Found`, if only the method differs it answers
+/// `405 Method Not Allowed` with an `Allow` header.
+#[derive(Default)]
+pub struct Router {
+  routes: Vec<Route>,
+}
+
+impl Router {
+  pub fn new() -> Self {
+    Router::default()
+  }
+
+  pub fn route(mut self, method: Method, pattern: &str, handler: impl Handler) -> Self {
+    self.routes.push(Route {
+      method,
+      pattern: Pattern::parse(pattern),
+      handler: Box::new(handler),
+    });
+    self
+  }
+
+  pub fn get(self, pattern: &str, handler: impl Handler) -> Self {
+    self.route(Method::GET, pattern, handler)
+  }
+
+  pub fn post(self, pattern: &str, handler: impl Handler) -> Self {
+    self.route(Method::POST, pattern, handler)
+  }
+
+  pub fn put(self, pattern: &str, handler: impl Handler) -> Self {
+    self.route(Method::PUT, pattern, handler)
+  }
+
+  pub fn delete(self, pattern: &str, handler: impl Handler) -> Self {
+    self.route(Method::DELETE, pattern, handler)
+  }
+}
+
+impl Handler for Router {
+  fn handle(&self, mut request: Request) -> IoResult<Response> {
+    let path = request.url().path();
+    let mut allowed: Vec<Method> = Vec::new();
+
+    for route in &self.routes {
+      let params = match route.pattern.matches(path) {
+        Some(params) => params,
+        None => continue,
+      };
+      if route.method == *request.method() {
+        request.set_params(params);
+        return route.handler.handle(request);
+      }
+      if !allowed.contains(&route.method) {
+        allowed.push(route.method);
+      }
+    }
+
+    if allowed.is_empty() {
+      return Ok(Response::builder().status(Status::NotFound).into());
+    }
+
+    let allow = allowed
+      .iter()
+      .map(Method::as_str)
+      .collect::<Vec<_>>()
+      .join(", ");
+    Ok(
+      Response::builder()
+        .status(Status::MethodNotAllowed)
+        .header((HeaderName::ALLOW, allow))
+        .into(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request(method: &str, path: &str) -> Request {
+    Request::parse(format!("{} {} HTTP/1.1\r\n\r\n", method, path).as_bytes()).unwrap()
+  }
+
+  fn echo_param(name: &'static str) -> impl Handler {
+    move |request: Request| {
+      let value = request.param(name).unwrap_or("<none>").to_string();
+      Ok(Response::builder().body(value).into())
+    }
+  }
+
+  fn body(response: &Response) -> &[u8] {
+    response.body().as_bytes().unwrap()
+  }
+
+  #[test]
+  fn matches_patterns() {
+    let pattern = Pattern::parse("/users/:id/posts");
+    assert_eq!(
+      pattern.matches("/users/42/posts").unwrap().get("id"),
+      Some("42")
+    );
+    assert!(pattern.matches("/users//posts").is_none());
+    assert!(pattern.matches("/users/42").is_none());
+    assert!(pattern.matches("/users/42/posts/1").is_none());
+
+    let wildcard = Pattern::parse("/static/*rest");
+    assert_eq!(
+      wildcard
+        .matches("/static/css/site.css")
+        .unwrap()
+        .get("rest"),
+      Some("css/site.css")
+    );
+    assert_eq!(wildcard.matches("/static").unwrap().get("rest"), Some(""));
+    assert!(wildcard.matches("/other/file").is_none());
+
+    assert!(Pattern::parse("/").matches("/").is_some());
+  }
+
+  #[test]
+  fn dispatches_on_method_and_path() {
+    let router = Router::new()
+      .get("/users/:id", echo_param("id"))
+      .post("/users", |_| Ok(Response::builder().body("created").into()))
+      .get("/static/*path", echo_param("path"));
+
+    let response = router.handle(request("GET", "/users/7?full=1")).unwrap();
+    assert_eq!(body(&response), b"7");
+
+    let response = router.handle(request("POST", "/users")).unwrap();
+    assert_eq!(body(&response), b"created");
+
+    let response = router.handle(request("GET", "/static/js/app.js")).unwrap();
+    assert_eq!(body(&response), b"js/app.js");
+  }
+
+  #[test]
+  fn answers_404_and_405() {
+    let router = Router::new()
+      .get("/users/:id", echo_param("id"))
+      .delete("/users/:id", echo_param("id"));
+
+    let response = router.handle(request("GET", "/nope")).unwrap();
+    assert_eq!(response.status().code(), 404);
+
+    let response = router.handle(request("POST", "/users/7")).unwrap();
+    assert_eq!(response.status().code(), 405);
+    assert_eq!(response.headers().get("Allow"), Some("GET, DELETE"));
+  }
+}
@@ -1,67 +1,1055 @@
-use crate::net::http::{Request, Response};
+use crate::net::activation;
+use crate::net::http::common::Version;
+use crate::net::http::error::{default_error_response, ErrorMapper};
+use crate::net::http::listener::{Accept, Listener};
+use crate::net::http::middleware::{Middleware, Stack};
+use crate::net::http::pool::ThreadPool;
+use crate::net::http::reactor;
+use crate::net::http::reader::{FramingError, RequestReader};
+use crate::net::http::shutdown::{shutdown_on_signals, ShutdownHandle};
+use crate::net::http::{Handler, HeaderName, Limits, Method, Request, Response};
+use crate::net::memory::MemorySocket;
+use crate::net::options::SocketOptions;
 use crate::net::tcp::*;
+use crate::net::unix::{PeerCredentials, UnixListener};
+use nix::sys::socket::SockFlag;
 use std::io::Result as IoResult;
-use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
-use std::net::ToSocketAddrs;
-use std::time::SystemTime;
+use std::io::{Error, ErrorKind, Read};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-// const DEFAULT_MAX_HEADER_BYTES: u32 = 1 << 20;
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_REQUESTS: usize = 100;
+const DEFAULT_WORKERS: usize = 4;
+const DEFAULT_QUEUE_SIZE: usize = 64;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_BODY_READ_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often blocking waits wake up to check for a shutdown request.
+pub(crate) const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a rejected connection keeps discarding input before it is
+/// closed, so the client gets to read the error response.
+pub(crate) const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How the server waits for and drives connections.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+  /// Every connection is handed to a worker thread which blocks on it until
+  /// the connection closes.
+  Blocking,
+  /// Connections are put into non-blocking mode and multiplexed with epoll,
+  /// each worker thread running its own event loop.
+  Epoll,
+}
 
 pub struct Server {
-  inner: TcpListener<Socket>,
+  inner: Listener,
+  config: ConnectionConfig,
+  backend: Backend,
+  workers: usize,
+  queue_size: usize,
+  shutdown_timeout: Duration,
+  handle_signals: bool,
+  middleware: Vec<Arc<dyn Middleware>>,
+}
+
+/// Per-connection settings, shared with every worker thread.
+#[derive(Clone)]
+pub(crate) struct ConnectionConfig {
+  pub keep_alive_timeout: Duration,
+  pub max_requests: usize,
+  pub shutdown: ShutdownHandle,
+  pub error_mapper: Arc<dyn ErrorMapper>,
+  pub header_read_timeout: Option<Duration>,
+  pub body_read_timeout: Option<Duration>,
+  pub write_timeout: Option<Duration>,
+  pub limits: Arc<Limits>,
 }
 
 impl Server {
   pub fn bind(addr: impl ToSocketAddrs) -> Self {
     let listener = TcpListener::<Socket>::bind(addr)
       .unwrap_or_else(|e| panic!("error binding to address: {}", e));
-    Server { inner: listener }
+    Server::new(Listener::Tcp(listener))
+  }
+
+  /// Like `bind`, but configures the listening socket and every accepted
+  /// connection with `options` instead of the defaults.
+  pub fn bind_with(addr: impl ToSocketAddrs, options: SocketOptions) -> Self {
+    let listener = TcpListener::<Socket>::bind_with(addr, &options)
+      .unwrap_or_else(|e| panic!("error binding to address: {}", e));
+    Server::new(Listener::Tcp(listener))
+  }
+
+  /// Serves connections from an already bound TCP listener.
+  pub fn from_tcp(listener: TcpListener<Socket>) -> Self {
+    Server::new(Listener::Tcp(listener))
+  }
+
+  /// Serves the first listening socket passed by systemd socket activation,
+  /// so the unit can own a privileged port while we run unprivileged. Use
+  /// `activation::listen_fds` and `from_tcp` to pick among several.
+  pub fn from_systemd() -> Self {
+    let (_, listener) = activation::listen_fds()
+      .unwrap_or_else(|e| panic!("error adopting systemd sockets: {}", e))
+      .into_iter()
+      .next()
+      .unwrap_or_else(|| panic!("no sockets passed by systemd"));
+    Server::from_tcp(listener)
+  }
+
+  /// Listens on a Unix socket file at `path`, replacing a stale one left
+  /// behind by a previous run. Handlers can tell who is connecting through
+  /// `Request::peer_credentials`.
+  pub fn bind_unix(path: impl AsRef<Path>) -> Self {
+    let listener =
+      UnixListener::bind(path).unwrap_or_else(|e| panic!("error binding to unix socket: {}", e));
+    Server::new(Listener::Unix(listener))
+  }
+
+  /// Serves connections from an already bound Unix socket, e.g. one in the
+  /// abstract namespace or with restricted permissions.
+  pub fn from_unix(listener: UnixListener) -> Self {
+    Server::new(Listener::Unix(listener))
+  }
+
+  /// Serves connections queued on an in-memory listener, for tests that
+  /// drive the whole server without the network. Only `Backend::Blocking`
+  /// can serve it. See `MemorySocket`.
+  pub fn from_memory(socket: MemorySocket) -> Self {
+    let listener = TcpListener::from_memory(socket)
+      .unwrap_or_else(|e| panic!("error listening on memory socket: {}", e));
+    Server::new(Listener::Memory(listener))
+  }
+
+  fn new(listener: Listener) -> Self {
+    let shutdown =
+      ShutdownHandle::new().unwrap_or_else(|e| panic!("error creating shutdown handle: {}", e));
+    Server {
+      inner: listener,
+      config: ConnectionConfig {
+        keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+        max_requests: DEFAULT_MAX_REQUESTS,
+        shutdown,
+        error_mapper: Arc::new(default_error_response),
+        header_read_timeout: Some(DEFAULT_HEADER_READ_TIMEOUT),
+        body_read_timeout: Some(DEFAULT_BODY_READ_TIMEOUT),
+        write_timeout: Some(DEFAULT_WRITE_TIMEOUT),
+        limits: Arc::new(Limits::default()),
+      },
+      backend: Backend::Blocking,
+      workers: thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(DEFAULT_WORKERS),
+      queue_size: DEFAULT_QUEUE_SIZE,
+      shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+      handle_signals: false,
+      middleware: Vec::new(),
+    }
+  }
+
+  /// How long a persistent connection may sit idle between requests before
+  /// the server closes it.
+  pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+    self.config.keep_alive_timeout = timeout;
+    self
+  }
+
+  /// How many requests are served on a single connection before it is
+  /// closed. Setting this to `1` disables keep-alive altogether.
+  pub fn max_requests(mut self, max: usize) -> Self {
+    self.config.max_requests = max.max(1);
+    self
+  }
+
+  /// How long a client may take to send the request line and headers,
+  /// counted from when the request starts arriving (or, for the first
+  /// request, from when the connection was accepted). Slower clients get a
+  /// `408 Request Timeout`. Defaults to 30 seconds, `None` disables it.
+  pub fn header_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.config.header_read_timeout = timeout;
+    self
+  }
+
+  /// How long a client may take to send the body of a request once its
+  /// headers have arrived. Defaults to 5 minutes, `None` disables it.
+  pub fn body_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.config.body_read_timeout = timeout;
+    self
+  }
+
+  /// How long writing a response may stall because the client does not read
+  /// it before the connection is dropped. Defaults to 60 seconds, `None`
+  /// disables it.
+  pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.config.write_timeout = timeout;
+    self
+  }
+
+  /// Bounds the size of the request line, the headers and the body. See
+  /// `Limits` for the defaults.
+  pub fn limits(mut self, limits: Limits) -> Self {
+    self.config.limits = Arc::new(limits);
+    self
+  }
+
+  /// Selects the connection handling backend. Defaults to
+  /// `Backend::Blocking`.
+  pub fn backend(mut self, backend: Backend) -> Self {
+    self.backend = backend;
+    self
   }
 
-  pub fn serve<'a, F>(&self, handle_fn: F) -> IoResult<()>
+  /// Number of worker threads handling connections, or of event loops when
+  /// using `Backend::Epoll`. Defaults to the number of available CPUs.
+  pub fn workers(mut self, workers: usize) -> Self {
+    self.workers = workers.max(1);
+    self
+  }
+
+  /// How many accepted connections may wait for a free worker before the
+  /// accept loop stops taking new ones. Only used by `Backend::Blocking`.
+  pub fn queue_size(mut self, queue_size: usize) -> Self {
+    self.queue_size = queue_size;
+    self
+  }
+
+  /// How long `serve` waits for in-flight requests to finish once shutdown
+  /// has been requested.
+  pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+    self.shutdown_timeout = timeout;
+    self
+  }
+
+  /// Shuts the server down gracefully on SIGINT or SIGTERM.
+  pub fn handle_signals(mut self) -> Self {
+    self.handle_signals = true;
+    self
+  }
+
+  /// Runs `middleware` around the handler passed to `serve`. Middleware
+  /// added first is the outermost: it sees the request first and the
+  /// response last.
+  pub fn wrap(mut self, middleware: impl Middleware) -> Self {
+    self.middleware.push(Arc::new(middleware));
+    self
+  }
+
+  /// Decides what the client gets when the handler returns an error or
+  /// panics. By default an `HttpError` is answered with its status and
+  /// message and anything else with `500 Internal Server Error`.
+  pub fn error_mapper(mut self, mapper: impl ErrorMapper) -> Self {
+    self.config.error_mapper = Arc::new(mapper);
+    self
+  }
+
+  /// The address of the TCP socket we listen on. Fails when listening on a
+  /// Unix socket.
+  pub fn local_addr(&self) -> IoResult<SocketAddr> {
+    match &self.inner {
+      Listener::Tcp(listener) => listener.local_addr(),
+      Listener::Memory(listener) => listener.local_addr(),
+      Listener::Unix(_) => Err(Error::new(
+        ErrorKind::InvalidInput,
+        "server is listening on a unix socket",
+      )),
+    }
+  }
+
+  /// Returns a handle that makes `serve` stop accepting connections, drain
+  /// the open ones and return.
+  pub fn shutdown_handle(&self) -> ShutdownHandle {
+    self.config.shutdown.clone()
+  }
+
+  pub fn serve<H>(&self, handler: H) -> IoResult<()>
   where
-    F: Fn(Request) -> IoResult<Response>,
+    H: Handler,
   {
-    match self.inner.local_addr() {
+    match self.inner.describe() {
       Ok(addr) => info!("Server listening on {}", addr),
       Err(err) => error!("Error getting local address: {}", err),
     }
 
-    for stream in self.inner.incoming() {
-      let now = SystemTime::now();
-      let stream = stream?;
+    let _signals = if self.handle_signals {
+      Some(shutdown_on_signals(&self.config.shutdown)?)
+    } else {
+      None
+    };
 
-      let mut reader = BufReader::new(&stream);
-      let mut writer = BufWriter::new(&stream);
+    let handler = Stack::with_middleware(self.middleware.clone(), handler);
 
-      let buffer = &mut [0; 30000];
-      reader.read(buffer)?;
-      let raw_request = String::from_utf8_lossy(&buffer[..]);
-
-      let request =
-        Request::parse(&raw_request).map_err(|_| Error::from(ErrorKind::InvalidInput))?;
+    match &self.inner {
+      Listener::Tcp(listener) => self.serve_on(listener, handler),
+      Listener::Unix(listener) => self.serve_on(listener, handler),
+      Listener::Memory(_) if self.backend == Backend::Epoll => Err(Error::new(
+        ErrorKind::InvalidInput,
+        "in-memory sockets can only be served by Backend::Blocking",
+      )),
+      Listener::Memory(listener) => self.serve_blocking(listener, handler),
+    }
+  }
 
-      info!("{}", raw_request);
+  fn serve_on<L, H>(&self, listener: &L, handler: H) -> IoResult<()>
+  where
+    L: Accept + AsRawFd,
+    L::Socket: AsRawFd,
+    H: Handler,
+  {
+    match self.backend {
+      Backend::Blocking => self.serve_blocking(listener, handler),
+      Backend::Epoll => reactor::run(
+        listener,
+        &self.config,
+        &handler,
+        self.workers,
+        self.shutdown_timeout,
+      ),
+    }
+  }
 
-      let response = handle_fn(request)?;
+  fn serve_blocking<L, H>(&self, listener: &L, handler: H) -> IoResult<()>
+  where
+    L: Accept,
+    H: Handler,
+  {
+    let pool = ThreadPool::new(self.workers, self.queue_size)?;
+    let handler = Arc::new(handler);
 
-      writer.write_all(&response.as_bytes())?;
+    while !self.config.shutdown.is_shutdown() {
+      match listener.poll_accept(SHUTDOWN_POLL_INTERVAL) {
+        Ok(true) => {}
+        Ok(false) => continue,
+        Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+        Err(err) => return Err(err),
+      }
 
-      match now.elapsed() {
-        Ok(elapsed) => {
-          // it prints '2'
-          info!(
-            "took: {} microsecs ({} secs)",
-            elapsed.as_micros(),
-            elapsed.as_secs()
-          );
+      // Failing to accept one connection, e.g. because it was reset or we
+      // ran out of file descriptors, must not stop the server.
+      let (stream, credentials) = match listener.accept_connection(SockFlag::empty()) {
+        Ok(accepted) => accepted,
+        Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+        Err(err) => {
+          error!("Error accepting connection: {}", err);
+          thread::sleep(SHUTDOWN_POLL_INTERVAL);
+          continue;
         }
-        Err(e) => {
-          // an error occurred!
-          error!("Error: {:?}", e);
+      };
+      let handler = Arc::clone(&handler);
+      let config = self.config.clone();
+
+      pool.execute(move || {
+        if let Err(err) = handle_connection(&stream, credentials, &config, &*handler) {
+          error!("Error handling connection: {}", err);
         }
-      }
+      })?;
     }
+
+    info!("Shutting down, waiting for open connections to finish");
+    pool.join(self.shutdown_timeout);
     Ok(())
   }
 }
+
+fn handle_connection<S, H>(
+  stream: &TcpStream<S>,
+  credentials: Option<PeerCredentials>,
+  config: &ConnectionConfig,
+  handler: &H,
+) -> IoResult<()>
+where
+  S: SocketLike,
+  H: Handler,
+{
+  let mut reader = RequestReader::new(config.limits.clone())
+    .timeouts(config.header_read_timeout, config.body_read_timeout);
+  let mut served = 0;
+
+  if config.write_timeout.is_some() {
+    stream.set_write_timeout(config.write_timeout)?;
+  }
+
+  loop {
+    // Waiting for the first request counts against its head timeout, so
+    // the client does not get that budget twice.
+    let idle_timeout = if served > 0 {
+      Some(config.keep_alive_timeout)
+    } else {
+      reader.start_head()
+    };
+    if !reader.has_buffered() && !wait_for_request(stream, config, idle_timeout)? {
+      debug!("Closing idle connection after {} request(s)", served);
+      return Ok(());
+    }
+
+    let mut request = match reader.read_request(stream) {
+      Ok(Some(request)) => request,
+      // The client closed its end of the connection.
+      Ok(None) => return Ok(()),
+      Err(FramingError::Io(err)) => return Err(err),
+      Err(err) => {
+        info!("Rejecting request: {}", err);
+        err.response().into_encoder().send_to(stream)?;
+        linger(stream);
+        return Ok(());
+      }
+    };
+
+    request.set_peer_credentials(credentials);
+    served += 1;
+    let (response, keep_alive) = respond(request, served, config, handler);
+
+    response.into_encoder().send_to(stream)?;
+
+    if !keep_alive {
+      return Ok(());
+    }
+  }
+}
+
+/// Closing a socket with unread input makes the kernel reset the connection,
+/// which can discard a response the client has not read yet. Shut down the
+/// write side instead and discard input until the client closes its end or
+/// `LINGER_TIMEOUT` has passed.
+fn linger<S: SocketLike>(mut stream: &TcpStream<S>) {
+  if stream.shutdown(Shutdown::Write).is_err() {
+    return;
+  }
+  let deadline = Instant::now() + LINGER_TIMEOUT;
+  let mut buf = [0; 16 * 1024];
+  loop {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining == Duration::from_secs(0) || stream.set_read_timeout(Some(remaining)).is_err() {
+      return;
+    }
+    match stream.read(&mut buf) {
+      Ok(0) => return,
+      Ok(_) => {}
+      Err(ref err) if err.kind() == ErrorKind::Interrupted => {}
+      Err(_) => return,
+    }
+  }
+}
+
+/// Waits for the next request to start arriving. Gives up when the server
+/// shuts down or once `idle_timeout` has passed.
+fn wait_for_request<S: SocketLike>(
+  stream: &TcpStream<S>,
+  config: &ConnectionConfig,
+  idle_timeout: Option<Duration>,
+) -> IoResult<bool> {
+  let started = Instant::now();
+  loop {
+    if config.shutdown.is_shutdown() {
+      return Ok(false);
+    }
+    if matches!(idle_timeout, Some(timeout) if started.elapsed() >= timeout) {
+      return Ok(false);
+    }
+
+    match stream.wait_readable(SHUTDOWN_POLL_INTERVAL) {
+      Ok(true) => return Ok(true),
+      Ok(false) => {}
+      Err(ref err) if err.kind() == ErrorKind::Interrupted => {}
+      Err(err) => return Err(err),
+    }
+  }
+}
+
+/// Runs the handler on a request and marks the response as persistent or
+/// not. `served` counts this request. Returns the response and whether the
+/// connection should stay open after it is sent. A handler that fails or
+/// panics only affects this request: its error is turned into a response by
+/// the configured `ErrorMapper`.
+pub(crate) fn respond<H>(
+  request: Request,
+  served: usize,
+  config: &ConnectionConfig,
+  handler: &H,
+) -> (Response, bool)
+where
+  H: Handler,
+{
+  let now = SystemTime::now();
+
+  info!(
+    "{:?} {} {}",
+    request.method(),
+    request.url().path(),
+    request.version()
+  );
+
+  let mut keep_alive =
+    request.keep_alive() && served < config.max_requests && !config.shutdown.is_shutdown();
+  let version = *request.version();
+  let head = *request.method() == Method::HEAD;
+
+  let result = panic::catch_unwind(AssertUnwindSafe(|| handler.handle(request)))
+    .unwrap_or_else(|_| Err(Error::other("handler panicked")));
+  let mut response = match result {
+    Ok(response) => response,
+    Err(err) => {
+      error!("Error handling request: {}", err);
+      config.error_mapper.map_error(&err)
+    }
+  };
+  if response.has_bodiless_status() {
+    // Whatever the handler set, no body may follow.
+    response.headers_mut().remove(HeaderName::TRANSFER_ENCODING);
+    response.omit_body();
+  } else if response.body().len().is_none() && !response.is_chunked() {
+    if version >= Version::new(1, 1) {
+      response
+        .headers_mut()
+        .insert(HeaderName::TRANSFER_ENCODING, "chunked");
+    } else {
+      // HTTP/1.0 clients cannot read chunked bodies, so the end of the body
+      // is signalled by closing the connection.
+      keep_alive = false;
+    }
+  }
+  if head {
+    response.omit_body();
+  }
+  let connection = if keep_alive { "keep-alive" } else { "close" };
+  response
+    .headers_mut()
+    .insert(HeaderName::CONNECTION, connection);
+
+  match now.elapsed() {
+    Ok(elapsed) => {
+      info!(
+        "took: {} microsecs ({} secs)",
+        elapsed.as_micros(),
+        elapsed.as_secs()
+      );
+    }
+    Err(e) => {
+      // an error occurred!
+      error!("Error: {:?}", e);
+    }
+  }
+
+  (response, keep_alive)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::net::http::{Body, HttpError, Status};
+  use std::io::{Read, Write};
+  use std::net::TcpStream as StdTcpStream;
+
+  fn get(addr: SocketAddr) -> String {
+    send(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+  }
+
+  fn send(addr: SocketAddr, request: &str) -> String {
+    let mut stream = StdTcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+  }
+
+  fn spawn<H>(backend: Backend, handler: H) -> (SocketAddr, ShutdownHandle, Serving)
+  where
+    H: Handler,
+  {
+    spawn_with(backend, |server| server, handler)
+  }
+
+  /// Like `spawn`, with the server further set up by `configure`.
+  fn spawn_with<C, H>(
+    backend: Backend,
+    configure: C,
+    handler: H,
+  ) -> (SocketAddr, ShutdownHandle, Serving)
+  where
+    C: FnOnce(Server) -> Server,
+    H: Handler,
+  {
+    let options = SocketOptions::new().reuse_address(true).nodelay(true);
+    let server = configure(
+      Server::bind_with("127.0.0.1:0", options)
+        .backend(backend)
+        .workers(2),
+    );
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    (addr, shutdown, thread::spawn(move || server.serve(handler)))
+  }
+
+  type Serving = thread::JoinHandle<IoResult<()>>;
+
+  fn assert_shuts_down(backend: Backend) {
+    let (addr, shutdown, serving) = spawn(backend, |_| {
+      Ok(Response::builder().body(b"hi".to_vec()).into())
+    });
+
+    assert!(get(addr).ends_with("\r\n\r\nhi"));
+
+    // An idle keep-alive connection must not hold up the shutdown.
+    let _idle = StdTcpStream::connect(addr).unwrap();
+
+    shutdown.shutdown();
+    assert!(serving.join().unwrap().is_ok());
+  }
+
+  fn assert_streams_chunked(backend: Backend) {
+    let (addr, shutdown, serving) = spawn(backend, |_| {
+      let chunks = vec![b"hello ".to_vec(), b"world".to_vec()];
+      Ok(
+        Response::builder()
+          .body(Body::from_chunks(chunks.into_iter()))
+          .into(),
+      )
+    });
+
+    let response = get(addr);
+    assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+    assert!(response.ends_with("\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n"));
+
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+  }
+
+  fn assert_isolates_errors(backend: Backend) {
+    let (addr, shutdown, serving) = spawn(backend, |request: Request| match request.url().path() {
+      "/fail" => Err(Error::other("boom")),
+      "/missing" => Err(HttpError::new(Status::NotFound, "No such thing").into()),
+      "/panic" => panic!("handler panicked on purpose"),
+      _ => Ok(Response::builder().body("ok").into()),
+    });
+
+    let status_of = |request: &str| send(addr, request)[..12].to_string();
+    let get = |path: &str| format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+
+    assert_eq!(status_of("GARBAGE\r\n\r\n"), "HTTP/1.1 400");
+    assert_eq!(status_of("NOT A REQUEST\r\n\r\n"), "HTTP/1.1 501");
+    assert_eq!(status_of("GET / HTTP/2.0\r\n\r\n"), "HTTP/1.1 505");
+    assert_eq!(status_of(&get("/fail")), "HTTP/1.1 500");
+    assert_eq!(status_of(&get("/panic")), "HTTP/1.1 500");
+    assert!(send(addr, &get("/missing")).ends_with("\r\n\r\nNo such thing"));
+    assert_eq!(status_of(&get("/")), "HTTP/1.1 200");
+
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+  }
+
+  fn assert_serves_unix_sockets(backend: Backend) {
+    let path = std::env::temp_dir().join(format!(
+      "scratch-server-{:?}-{}.sock",
+      backend,
+      std::process::id()
+    ));
+    let server = Server::bind_unix(&path).backend(backend).workers(2);
+    let shutdown = server.shutdown_handle();
+    let serving = thread::spawn(move || {
+      server.serve(|request: Request| {
+        let pid = request.peer_credentials().map(|creds| creds.pid());
+        Ok(Response::builder().body(format!("{:?}", pid)).into())
+      })
+    });
+
+    let mut stream = std::os::unix::net::UnixStream::connect(&path).unwrap();
+    stream
+      .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+      .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with(&format!("Some({})", std::process::id())));
+
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+    assert!(!path.exists());
+  }
+
+  fn assert_times_out_slow_requests(backend: Backend) {
+    let (addr, shutdown, serving) = spawn_with(
+      backend,
+      |server| server.header_read_timeout(Some(Duration::from_millis(200))),
+      |_| Ok(Response::builder().body("ok").into()),
+    );
+
+    // The head never completes, so the server gives up on it.
+    let response = send(addr, "GET / HTTP/1.1\r\nHost: slow");
+    assert!(response.starts_with("HTTP/1.1 408"));
+
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+  }
+
+  fn assert_sends_files(backend: Backend) {
+    // Large enough to take several sendfile calls on a non-blocking socket.
+    let content: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let path = std::env::temp_dir().join(format!(
+      "scratch-sendfile-{:?}-{}",
+      backend,
+      std::process::id()
+    ));
+    std::fs::write(&path, &content).unwrap();
+    let served = path.clone();
+    let (addr, shutdown, serving) = spawn(backend, move |request: Request| {
+      let file = std::fs::File::open(&served)?;
+      let body = match request.url().path() {
+        "/range" => Body::from_file_range(file, 10, 20)?,
+        _ => Body::from_file(file)?,
+      };
+      Ok(Response::builder().body(body).into())
+    });
+
+    let mut stream = StdTcpStream::connect(addr).unwrap();
+    stream
+      .write_all(b"GET /range HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+      .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let mut expected = b"Content-Length: 20\r\n\r\n".to_vec();
+    expected.extend_from_slice(&content[10..30]);
+    expected.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
+    assert!(response
+      .windows(expected.len())
+      .any(|window| window == &expected[..]));
+    assert!(response.ends_with(&content));
+
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn serves_memory_sockets() {
+    let listener = MemorySocket::new();
+    let remote = listener.peer();
+    let server = Server::from_memory(listener).workers(1);
+    let shutdown = server.shutdown_handle();
+    let serving = thread::spawn(move || {
+      server.serve(|request: Request| Ok(Response::builder().body(request.url().path()).into()))
+    });
+
+    // Two pipelined requests split at odd places, then the client hangs up.
+    let pipelined = MemorySocket::new()
+      .read_chunk("GET /one HTTP/1.1\r\nHo")
+      .read_chunk("st: x\r\n\r\nGET /two HTTP/1.1\r\n\r\n");
+    let client = pipelined.peer();
+    remote.connect(pipelined);
+    assert!(client.wait_closed(Duration::from_secs(5)));
+    let written = String::from_utf8(client.written()).unwrap();
+    assert_eq!(written.matches("HTTP/1.1 200").count(), 2);
+    assert!(written.ends_with("\r\n\r\n/two"));
+
+    // A connection reset mid-request is dropped without an answer.
+    let reset = MemorySocket::new()
+      .read_chunk("GET / HTTP/1.1\r\n")
+      .read_error(ErrorKind::ConnectionReset);
+    let client = reset.peer();
+    remote.connect(reset);
+    assert!(client.wait_closed(Duration::from_secs(5)));
+    assert!(client.written().is_empty());
+
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+  }
+
+  #[test]
+  fn blocking_backend_shuts_down() {
+    assert_shuts_down(Backend::Blocking);
+  }
+
+  #[test]
+  fn epoll_backend_shuts_down() {
+    assert_shuts_down(Backend::Epoll);
+  }
+
+  #[test]
+  fn blocking_backend_streams_chunked() {
+    assert_streams_chunked(Backend::Blocking);
+  }
+
+  #[test]
+  fn epoll_backend_streams_chunked() {
+    assert_streams_chunked(Backend::Epoll);
+  }
+
+  #[test]
+  fn blocking_backend_isolates_errors() {
+    assert_isolates_errors(Backend::Blocking);
+  }
+
+  #[test]
+  fn epoll_backend_isolates_errors() {
+    assert_isolates_errors(Backend::Epoll);
+  }
+
+  #[test]
+  fn blocking_backend_serves_unix_sockets() {
+    assert_serves_unix_sockets(Backend::Blocking);
+  }
+
+  #[test]
+  fn epoll_backend_serves_unix_sockets() {
+    assert_serves_unix_sockets(Backend::Epoll);
+  }
+
+  #[test]
+  fn sends_no_body_where_none_may_follow() {
+    let (addr, shutdown, serving) = spawn(Backend::Epoll, |request: Request| {
+      let response = match request.url().path() {
+        "/empty" => Response::builder()
+          .status(Status::NoContent)
+          .body(Body::from_chunks(vec![b"oops".to_vec()].into_iter())),
+        _ => Response::builder().body("hello"),
+      };
+      Ok(response.into())
+    });
+
+    // Any stray body would be read as the start of the next response.
+    let response = send(
+      addr,
+      "HEAD / HTTP/1.1\r\n\r\nGET /empty HTTP/1.1\r\n\r\n\
+       GET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    let responses: Vec<&str> = response.split("HTTP/1.1 ").skip(1).collect();
+    assert_eq!(responses.len(), 3);
+    assert!(responses[0].starts_with("200"));
+    assert!(responses[0].contains("Content-Length: 5\r\n"));
+    assert!(responses[0].ends_with("\r\n\r\n"));
+    assert!(responses[1].starts_with("204"));
+    assert!(!responses[1].contains("Transfer-Encoding"));
+    assert!(responses[1].ends_with("\r\n\r\n"));
+    assert!(responses[2].ends_with("\r\n\r\nhello"));
+
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+  }
+
+  #[test]
+  fn keeps_slow_requests_past_the_keep_alive_timeout() {
+    let (addr, shutdown, serving) = spawn_with(
+      Backend::Epoll,
+      |server| server.keep_alive_timeout(Duration::from_millis(200)),
+      |request: Request| {
+        Ok(
+          Response::builder()
+            .body(request.body_bytes().to_vec())
+            .into(),
+        )
+      },
+    );
+
+    // The body stalls for longer than an idle connection may stay open, but
+    // well within the body read timeout.
+    let mut stream = StdTcpStream::connect(addr).unwrap();
+    stream
+      .write_all(b"POST / HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbo")
+      .unwrap();
+    thread::sleep(Duration::from_millis(1300));
+    stream.write_all(b"dy").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.ends_with("\r\n\r\nbody"));
+
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+  }
+
+  fn assert_answers_after_half_close(backend: Backend) {
+    let (addr, shutdown, serving) = spawn(backend, |request: Request| {
+      Ok(
+        Response::builder()
+          .body(request.url().path().to_string())
+          .into(),
+      )
+    });
+
+    // Both requests arrive along with the end of the input.
+    let mut stream = StdTcpStream::connect(addr).unwrap();
+    stream
+      .write_all(b"GET /one HTTP/1.1\r\n\r\nGET /two HTTP/1.1\r\n\r\n")
+      .unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.contains("\r\n\r\n/one"));
+    assert!(response.ends_with("\r\n\r\n/two"));
+
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+  }
+
+  #[test]
+  fn blocking_backend_answers_after_half_close() {
+    assert_answers_after_half_close(Backend::Blocking);
+  }
+
+  #[test]
+  fn epoll_backend_answers_after_half_close() {
+    assert_answers_after_half_close(Backend::Epoll);
+  }
+
+  fn assert_holds_back_pipelined_requests(backend: Backend) {
+    let handled = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = handled.clone();
+    let (addr, shutdown, serving) = spawn_with(
+      backend,
+      |server| server.max_requests(usize::MAX),
+      move |_| {
+        counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Ok(Response::builder().body(vec![b'x'; 64 * 1024]).into())
+      },
+    );
+
+    // The client sends far more requests than the socket buffers can hold
+    // responses for, and never reads any of them.
+    let stream = StdTcpStream::connect(addr).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let requests = "GET / HTTP/1.1\r\n\r\n".repeat(10_000);
+    let writing = thread::spawn(move || {
+      let _ = writer.write_all(requests.as_bytes());
+    });
+    thread::sleep(Duration::from_millis(300));
+    assert!(handled.load(std::sync::atomic::Ordering::SeqCst) < 1_000);
+
+    stream.shutdown(std::net::Shutdown::Both).unwrap();
+    writing.join().unwrap();
+    drop(stream);
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+  }
+
+  #[test]
+  fn blocking_backend_holds_back_pipelined_requests() {
+    assert_holds_back_pipelined_requests(Backend::Blocking);
+  }
+
+  #[test]
+  fn epoll_backend_holds_back_pipelined_requests() {
+    assert_holds_back_pipelined_requests(Backend::Epoll);
+  }
+
+  fn assert_enforces_limits(backend: Backend) {
+    let (addr, shutdown, serving) = spawn_with(
+      backend,
+      |server| server.limits(Limits::default().max_request_line(64).max_body_bytes(4)),
+      |_| Ok(Response::builder().body("ok").into()),
+    );
+
+    // The rest of each request is never read, so the connection is closed
+    // after the error.
+    let response = send(addr, &format!("GET /{} HTTP/1.1", "a".repeat(100)));
+    assert!(response.starts_with("HTTP/1.1 414"));
+    assert!(response.contains("Connection: close\r\n"));
+    let response = send(addr, "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 413"));
+
+    // The server answers before the upload is through. A client that only
+    // reads once it has sent everything must still get to see the answer,
+    // rather than have the connection reset under it.
+    let mut stream = StdTcpStream::connect(addr).unwrap();
+    let upload = vec![b'x'; 16 << 20];
+    write!(
+      stream,
+      "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+      upload.len()
+    )
+    .unwrap();
+    stream.write_all(&upload).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 413"));
+    drop(stream);
+
+    let response = send(
+      addr,
+      "POST / HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nfour",
+    );
+    assert!(response.starts_with("HTTP/1.1 200"));
+
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+  }
+
+  #[test]
+  fn blocking_backend_enforces_limits() {
+    assert_enforces_limits(Backend::Blocking);
+  }
+
+  #[test]
+  fn epoll_backend_enforces_limits() {
+    assert_enforces_limits(Backend::Epoll);
+  }
+
+  #[test]
+  fn times_each_phase_from_its_start() {
+    let (addr, shutdown, serving) = spawn_with(
+      Backend::Blocking,
+      |server| {
+        server
+          .header_read_timeout(Some(Duration::from_millis(200)))
+          .body_read_timeout(None)
+      },
+      |request: Request| {
+        Ok(
+          Response::builder()
+            .body(request.body_bytes().to_vec())
+            .into(),
+        )
+      },
+    );
+    let slowly = |parts: &[&str]| {
+      let mut stream = StdTcpStream::connect(addr).unwrap();
+      for part in parts {
+        thread::sleep(Duration::from_millis(150));
+        stream.write_all(part.as_bytes()).unwrap();
+      }
+      let mut response = String::new();
+      stream.read_to_string(&mut response).unwrap();
+      response
+    };
+
+    // Waiting for the first byte already counts against the head timeout.
+    let response = slowly(&["GET / HTTP/1.1\r\n", "Connection: close\r\n\r\n"]);
+    assert!(response.starts_with("HTTP/1.1 408"));
+
+    // The body has no timeout, so it may arrive long after the head's.
+    let response = slowly(&[
+      "POST / HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\n",
+      "",
+      "",
+      "slow",
+    ]);
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.ends_with("slow"));
+
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+  }
+
+  #[test]
+  fn blocking_backend_times_out_slow_requests() {
+    assert_times_out_slow_requests(Backend::Blocking);
+  }
+
+  #[test]
+  fn epoll_backend_times_out_slow_requests() {
+    assert_times_out_slow_requests(Backend::Epoll);
+  }
+
+  #[test]
+  fn blocking_backend_sends_files() {
+    assert_sends_files(Backend::Blocking);
+  }
+
+  #[test]
+  fn epoll_backend_sends_files() {
+    assert_sends_files(Backend::Epoll);
+  }
+}
@@ -0,0 +1,203 @@
+use crate::net::util::into_io_error;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc::c_int;
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{close, pipe2, read, write};
+use std::io::Result as IoResult;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Lets other threads ask a running `Server` to stop. Once triggered the
+/// server stops accepting, closes idle connections, waits for in-flight
+/// requests and returns from `serve`.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+  inner: Arc<State>,
+}
+
+struct State {
+  requested: AtomicBool,
+  /// Becomes readable once shutdown is requested so event loops blocked in
+  /// `epoll_wait` wake up immediately.
+  event: RawFd,
+}
+
+impl ShutdownHandle {
+  pub(crate) fn new() -> IoResult<Self> {
+    let event =
+      eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK).map_err(into_io_error)?;
+    Ok(ShutdownHandle {
+      inner: Arc::new(State {
+        requested: AtomicBool::new(false),
+        event,
+      }),
+    })
+  }
+
+  pub fn shutdown(&self) {
+    if !self.inner.requested.swap(true, Ordering::SeqCst) {
+      let _ = write(self.inner.event, &1u64.to_ne_bytes());
+    }
+  }
+
+  pub fn is_shutdown(&self) -> bool {
+    self.inner.requested.load(Ordering::SeqCst)
+  }
+
+  pub(crate) fn event_fd(&self) -> RawFd {
+    self.inner.event
+  }
+}
+
+impl Drop for State {
+  fn drop(&mut self) {
+    let _ = close(self.event);
+  }
+}
+
+// ----- Signal handling ------
+
+/// Write end of the self-pipe the signal handler pokes. Writing to a pipe is
+/// one of the few things that is safe to do inside a signal handler.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+static SIGNAL_HANDLES: Mutex<Vec<ShutdownHandle>> = Mutex::new(Vec::new());
+
+extern "C" fn on_signal(_: c_int) {
+  let fd = SIGNAL_PIPE.load(Ordering::SeqCst);
+  if fd >= 0 {
+    let _ = write(fd, &[1]);
+  }
+}
+
+/// Triggers `handle` on the first SIGINT or SIGTERM, until the returned
+/// guard is dropped. A second signal is left to the default disposition, so
+/// pressing Ctrl-C twice still kills the process straight away; so is any
+/// signal once no handle is registered.
+pub(crate) fn shutdown_on_signals(handle: &ShutdownHandle) -> IoResult<SignalGuard> {
+  let mut handles = SIGNAL_HANDLES.lock().unwrap_or_else(|e| e.into_inner());
+  handles.push(handle.clone());
+  let guard = SignalGuard(handle.clone());
+
+  if SIGNAL_PIPE.load(Ordering::SeqCst) >= 0 {
+    return Ok(guard);
+  }
+
+  let (reader, writer) = pipe2(OFlag::O_CLOEXEC).map_err(into_io_error)?;
+  SIGNAL_PIPE.store(writer, Ordering::SeqCst);
+
+  thread::Builder::new()
+    .name("scratch-signals".to_string())
+    .spawn(move || {
+      let mut buf = [0; 1];
+      // Runs until `uninstall` closes the write end.
+      loop {
+        match read(reader, &mut buf) {
+          Ok(0) => break,
+          Ok(_) => {
+            info!("Received shutdown signal");
+            let handles = SIGNAL_HANDLES.lock().unwrap_or_else(|e| e.into_inner());
+            for handle in handles.iter() {
+              handle.shutdown();
+            }
+            uninstall();
+          }
+          Err(err) if err.as_errno() == Some(Errno::EINTR) => continue,
+          Err(_) => break,
+        }
+      }
+      let _ = close(reader);
+    })?;
+
+  set_signal_handler(SigHandler::Handler(on_signal))?;
+  Ok(guard)
+}
+
+/// Stops routing signals to a handle when dropped. The last one to go puts
+/// back the default disposition.
+pub(crate) struct SignalGuard(ShutdownHandle);
+
+impl Drop for SignalGuard {
+  fn drop(&mut self) {
+    let mut handles = SIGNAL_HANDLES.lock().unwrap_or_else(|e| e.into_inner());
+    handles.retain(|handle| !Arc::ptr_eq(&handle.inner, &self.0.inner));
+    if handles.is_empty() {
+      uninstall();
+    }
+  }
+}
+
+/// Restores the default disposition and stops the watcher thread, so the
+/// next `shutdown_on_signals` installs them afresh. Called with
+/// `SIGNAL_HANDLES` locked.
+fn uninstall() {
+  let _ = set_signal_handler(SigHandler::SigDfl);
+  let writer = SIGNAL_PIPE.swap(-1, Ordering::SeqCst);
+  if writer >= 0 {
+    let _ = close(writer);
+  }
+}
+
+fn set_signal_handler(handler: SigHandler) -> IoResult<()> {
+  // No SA_RESTART: blocking calls should return EINTR so they notice.
+  let action = SigAction::new(handler, SaFlags::empty(), SigSet::empty());
+  for signal in &[Signal::SIGINT, Signal::SIGTERM] {
+    unsafe { sigaction(*signal, &action) }.map_err(into_io_error)?;
+  }
+  Ok(())
+}
+
+// ----- End signal handling ------
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn signals_reach_whoever_is_registered() {
+    let wait_for = |handle: &ShutdownHandle| {
+      for _ in 0..500 {
+        if handle.is_shutdown() {
+          return;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+      }
+      panic!("the signal was not delivered");
+    };
+
+    let first = ShutdownHandle::new().unwrap();
+    let guard = shutdown_on_signals(&first).unwrap();
+    nix::sys::signal::raise(Signal::SIGTERM).unwrap();
+    wait_for(&first);
+    drop(guard);
+
+    // The watcher is set up again for a later server.
+    let second = ShutdownHandle::new().unwrap();
+    let _guard = shutdown_on_signals(&second).unwrap();
+    nix::sys::signal::raise(Signal::SIGTERM).unwrap();
+    wait_for(&second);
+
+    let gone = ShutdownHandle::new().unwrap();
+    drop(shutdown_on_signals(&gone).unwrap());
+    let handles = SIGNAL_HANDLES.lock().unwrap();
+    assert!(!handles
+      .iter()
+      .any(|handle| Arc::ptr_eq(&handle.inner, &gone.inner)));
+  }
+
+  #[test]
+  fn shutdown_is_shared_between_clones() {
+    let handle = ShutdownHandle::new().unwrap();
+    let clone = handle.clone();
+    assert!(!handle.is_shutdown());
+
+    clone.shutdown();
+    assert!(handle.is_shutdown());
+
+    let mut buf = [0; 8];
+    assert_eq!(read(handle.event_fd(), &mut buf).unwrap(), 8);
+  }
+}
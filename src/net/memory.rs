@@ -0,0 +1,422 @@
+use super::options::{SocketOptions, DEFAULT_BACKLOG};
+use super::tcp::{SocketLike, TcpListener};
+use nix::sys::socket::{AddressFamily, SockFlag};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// What the next read of a `MemorySocket` returns.
+enum Step {
+  Data(Vec<u8>),
+  Error(ErrorKind),
+  Eof,
+}
+
+#[derive(Default)]
+struct State {
+  reads: VecDeque<Step>,
+  write_errors: VecDeque<ErrorKind>,
+  written: Vec<u8>,
+  /// Connections waiting to be accepted when used as a listener.
+  pending: VecDeque<MemorySocket>,
+  listening: bool,
+  nonblocking: bool,
+  read_shut: bool,
+  write_shut: bool,
+  /// Live `MemorySocket`s sharing this state, clones included.
+  handles: usize,
+  local_addr: Option<SocketAddr>,
+  peer_addr: Option<SocketAddr>,
+}
+
+#[derive(Default)]
+struct Shared {
+  state: Mutex<State>,
+  changed: Condvar,
+}
+
+impl Shared {
+  fn lock(&self) -> MutexGuard<'_, State> {
+    // A test that panicked while holding the lock already failed; keep the
+    // state readable for the others.
+    self.state.lock().unwrap_or_else(|err| err.into_inner())
+  }
+}
+
+/// An in-memory `SocketLike` for tests. Reads return a script of chunks,
+/// errors and ends of file set up front, and writes are captured so they
+/// can be inspected through a `MemoryPeer`. Reading past the end of the
+/// script returns end of file.
+///
+/// A `MemorySocket` can also act as a listener: `MemoryPeer::connect`
+/// queues connections for it to accept, so a whole `Server` can be driven
+/// without touching the network.
+///
+/// ```ignore
+/// let conn = MemorySocket::new().read_chunk("GET / HTTP/1.1\r\n\r\n");
+/// let client = conn.peer();
+/// listener_peer.connect(conn);
+/// assert!(client.wait_closed(Duration::from_secs(1)));
+/// assert!(client.written().starts_with(b"HTTP/1.1 200"));
+/// ```
+pub struct MemorySocket {
+  shared: Arc<Shared>,
+}
+
+impl MemorySocket {
+  pub fn new() -> Self {
+    MemorySocket::from_shared(Arc::new(Shared::default()))
+  }
+
+  fn from_shared(shared: Arc<Shared>) -> Self {
+    shared.lock().handles += 1;
+    MemorySocket { shared }
+  }
+
+  /// Appends `data` to the script; a read returns at most this chunk.
+  pub fn read_chunk(self, data: impl Into<Vec<u8>>) -> Self {
+    let data = data.into();
+    if !data.is_empty() {
+      self.shared.lock().reads.push_back(Step::Data(data));
+    }
+    self
+  }
+
+  /// Appends a read failing with `kind` to the script.
+  pub fn read_error(self, kind: ErrorKind) -> Self {
+    self.shared.lock().reads.push_back(Step::Error(kind));
+    self
+  }
+
+  /// Appends an end of file to the script. Reads after it continue with
+  /// the rest of the script.
+  pub fn read_eof(self) -> Self {
+    self.shared.lock().reads.push_back(Step::Eof);
+    self
+  }
+
+  /// Makes the next write that is not already set to fail fail with `kind`.
+  pub fn write_error(self, kind: ErrorKind) -> Self {
+    self.shared.lock().write_errors.push_back(kind);
+    self
+  }
+
+  /// The address reported as the local end. Defaults to `127.0.0.1:0`.
+  pub fn local_addr(self, addr: SocketAddr) -> Self {
+    self.shared.lock().local_addr = Some(addr);
+    self
+  }
+
+  /// The address reported as the remote end. Defaults to `127.0.0.1:0`.
+  pub fn peer_addr(self, addr: SocketAddr) -> Self {
+    self.shared.lock().peer_addr = Some(addr);
+    self
+  }
+
+  /// A handle to watch this socket from the other end.
+  pub fn peer(&self) -> MemoryPeer {
+    MemoryPeer {
+      shared: Arc::clone(&self.shared),
+    }
+  }
+}
+
+impl Default for MemorySocket {
+  fn default() -> Self {
+    MemorySocket::new()
+  }
+}
+
+impl Drop for MemorySocket {
+  fn drop(&mut self) {
+    self.shared.lock().handles -= 1;
+    self.shared.changed.notify_all();
+  }
+}
+
+fn unspecified() -> SocketAddr {
+  SocketAddr::from(([127, 0, 0, 1], 0))
+}
+
+impl SocketLike for MemorySocket {
+  type Addr = SocketAddr;
+
+  fn new(_family: AddressFamily) -> IoResult<Box<MemorySocket>> {
+    Ok(Box::new(MemorySocket::new()))
+  }
+
+  fn accept(&self, flags: SockFlag) -> IoResult<Box<MemorySocket>> {
+    let mut state = self.shared.lock();
+    if !state.listening {
+      return Err(Error::new(
+        ErrorKind::InvalidInput,
+        "socket is not listening",
+      ));
+    }
+    loop {
+      if let Some(conn) = state.pending.pop_front() {
+        conn.shared.lock().nonblocking = flags.contains(SockFlag::SOCK_NONBLOCK);
+        return Ok(Box::new(conn));
+      }
+      if state.nonblocking {
+        return Err(Error::from(ErrorKind::WouldBlock));
+      }
+      state = self
+        .shared
+        .changed
+        .wait(state)
+        .unwrap_or_else(|err| err.into_inner());
+    }
+  }
+
+  fn get_peer_name(&self) -> IoResult<SocketAddr> {
+    Ok(self.shared.lock().peer_addr.unwrap_or_else(unspecified))
+  }
+
+  fn get_sock_name(&self) -> IoResult<SocketAddr> {
+    Ok(self.shared.lock().local_addr.unwrap_or_else(unspecified))
+  }
+
+  fn bind(&mut self, addr: SocketAddr) -> IoResult<()> {
+    self.shared.lock().local_addr = Some(addr);
+    Ok(())
+  }
+
+  fn connect(&mut self, addr: SocketAddr, _timeout: Option<Duration>) -> IoResult<()> {
+    self.shared.lock().peer_addr = Some(addr);
+    Ok(())
+  }
+
+  fn listen(&self, _backlog: usize) -> IoResult<()> {
+    self.shared.lock().listening = true;
+    Ok(())
+  }
+
+  fn shutdown(&self, how: Shutdown) -> IoResult<()> {
+    let mut state = self.shared.lock();
+    match how {
+      Shutdown::Read => state.read_shut = true,
+      Shutdown::Write => state.write_shut = true,
+      Shutdown::Both => {
+        state.read_shut = true;
+        state.write_shut = true;
+      }
+    }
+    self.shared.changed.notify_all();
+    Ok(())
+  }
+
+  fn try_clone(&self) -> IoResult<Box<MemorySocket>> {
+    Ok(Box::new(MemorySocket::from_shared(Arc::clone(
+      &self.shared,
+    ))))
+  }
+
+  fn close(&self) -> IoResult<()> {
+    self.shutdown(Shutdown::Both)
+  }
+
+  fn read(&self, buf: &mut [u8]) -> IoResult<usize> {
+    let mut state = self.shared.lock();
+    if state.read_shut {
+      return Ok(0);
+    }
+    match state.reads.pop_front() {
+      Some(Step::Data(mut data)) => {
+        let read = data.len().min(buf.len());
+        buf[..read].copy_from_slice(&data[..read]);
+        if read < data.len() {
+          data.drain(..read);
+          state.reads.push_front(Step::Data(data));
+        }
+        Ok(read)
+      }
+      Some(Step::Error(kind)) => Err(Error::from(kind)),
+      Some(Step::Eof) | None => Ok(0),
+    }
+  }
+
+  fn write(&self, buf: &[u8]) -> IoResult<usize> {
+    let mut state = self.shared.lock();
+    if let Some(kind) = state.write_errors.pop_front() {
+      return Err(Error::from(kind));
+    }
+    if state.write_shut {
+      return Err(Error::from(ErrorKind::BrokenPipe));
+    }
+    state.written.extend_from_slice(buf);
+    self.shared.changed.notify_all();
+    Ok(buf.len())
+  }
+
+  /// A connection is always readable, since the script is there or the end
+  /// of file is. A listener waits for a connection to be queued.
+  fn poll_read(&self, timeout: Duration) -> IoResult<bool> {
+    let state = self.shared.lock();
+    if !state.listening {
+      return Ok(true);
+    }
+    let (state, _) = self
+      .shared
+      .changed
+      .wait_timeout_while(state, timeout, |state| state.pending.is_empty())
+      .unwrap_or_else(|err| err.into_inner());
+    Ok(!state.pending.is_empty())
+  }
+
+  fn set_only_v6(&self, _only_v6: bool) -> IoResult<()> {
+    Err(Error::new(ErrorKind::InvalidInput, "not an IPv6 socket"))
+  }
+
+  fn only_v6(&self) -> IoResult<bool> {
+    Err(Error::new(ErrorKind::InvalidInput, "not an IPv6 socket"))
+  }
+
+  fn set_options(&self, _options: &SocketOptions) -> IoResult<()> {
+    Ok(())
+  }
+
+  /// Reads never wait, so there is nothing to time out.
+  fn set_read_timeout(&self, _timeout: Option<Duration>) -> IoResult<()> {
+    Ok(())
+  }
+
+  /// Writes never wait, so there is nothing to time out.
+  fn set_write_timeout(&self, _timeout: Option<Duration>) -> IoResult<()> {
+    Ok(())
+  }
+
+  fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+    self.shared.lock().nonblocking = nonblocking;
+    Ok(())
+  }
+}
+
+impl TcpListener<MemorySocket> {
+  /// Listens on `socket`. Connect to it through a `MemoryPeer` taken from
+  /// the socket beforehand.
+  pub fn from_memory(socket: MemorySocket) -> IoResult<Self> {
+    socket.listen(DEFAULT_BACKLOG)?;
+    Ok(TcpListener::from_inner(socket))
+  }
+}
+
+/// The test's view of a `MemorySocket`: what was written to it, whether it
+/// was closed, and, for listeners, a way to connect to it.
+#[derive(Clone)]
+pub struct MemoryPeer {
+  shared: Arc<Shared>,
+}
+
+impl MemoryPeer {
+  /// Everything written to the socket so far.
+  pub fn written(&self) -> Vec<u8> {
+    self.shared.lock().written.clone()
+  }
+
+  /// Whether the socket was dropped, along with all its clones, or shut
+  /// down for writing.
+  pub fn is_closed(&self) -> bool {
+    let state = self.shared.lock();
+    state.handles == 0 || state.write_shut
+  }
+
+  /// Waits up to `timeout` for the socket to be closed. Returns `false` if
+  /// it is still open.
+  pub fn wait_closed(&self, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut state = self.shared.lock();
+    while state.handles > 0 && !state.write_shut {
+      let remaining = deadline.saturating_duration_since(Instant::now());
+      if remaining == Duration::from_secs(0) {
+        return false;
+      }
+      state = self
+        .shared
+        .changed
+        .wait_timeout(state, remaining)
+        .unwrap_or_else(|err| err.into_inner())
+        .0;
+    }
+    true
+  }
+
+  /// Queues `conn` to be accepted by the listening socket.
+  pub fn connect(&self, conn: MemorySocket) {
+    self.shared.lock().pending.push_back(conn);
+    self.shared.changed.notify_all();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::net::tcp::TcpStream;
+  use std::io::{Read, Write};
+
+  #[test]
+  fn plays_the_read_script() {
+    let socket = MemorySocket::new()
+      .read_chunk("hello")
+      .read_error(ErrorKind::Interrupted)
+      .read_eof()
+      .read_chunk("again");
+    let mut buf = [0; 3];
+    assert_eq!(socket.read(&mut buf).unwrap(), 3);
+    assert_eq!(socket.read(&mut buf).unwrap(), 2);
+    assert_eq!(&buf[..2], b"lo");
+    assert_eq!(
+      socket.read(&mut buf).unwrap_err().kind(),
+      ErrorKind::Interrupted
+    );
+    assert_eq!(socket.read(&mut buf).unwrap(), 0);
+    assert_eq!(socket.read(&mut buf).unwrap(), 3);
+    assert_eq!(socket.read(&mut buf).unwrap(), 2);
+    assert_eq!(socket.read(&mut buf).unwrap(), 0);
+  }
+
+  #[test]
+  fn captures_writes_and_close() {
+    let socket = MemorySocket::new().write_error(ErrorKind::ConnectionReset);
+    let peer = socket.peer();
+    let listener_socket = MemorySocket::new();
+    let remote = listener_socket.peer();
+    let listener = TcpListener::from_memory(listener_socket).unwrap();
+    remote.connect(socket);
+
+    let (mut stream, _) = listener.accept().unwrap();
+    assert_eq!(
+      stream.write(b"lost").unwrap_err().kind(),
+      ErrorKind::ConnectionReset
+    );
+    stream.write_all(b"kept").unwrap();
+    let path = std::env::temp_dir().join(format!("scratch-memory-{}", std::process::id()));
+    std::fs::write(&path, b"--file--").unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(stream.send_file(&file, 2, 4).unwrap(), 4);
+    let clone = stream.try_clone().unwrap();
+    drop(stream);
+    assert!(!peer.is_closed());
+    drop(clone);
+    assert!(peer.wait_closed(Duration::from_secs(1)));
+    assert_eq!(peer.written(), b"keptfile");
+
+    listener.set_nonblocking(true).unwrap();
+    assert_eq!(
+      listener.accept().err().unwrap().kind(),
+      ErrorKind::WouldBlock
+    );
+  }
+
+  #[test]
+  fn reads_like_a_stream() {
+    let mut stream = TcpStream::<MemorySocket>::connect("127.0.0.1:80").unwrap();
+    assert_eq!(stream.peer_addr().unwrap().port(), 80);
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+  }
+}
@@ -1,3 +1,8 @@
+pub mod activation;
 pub mod http;
+pub mod memory;
+pub mod options;
 pub mod tcp;
+pub mod udp;
+pub mod unix;
 pub mod util;
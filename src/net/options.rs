@@ -0,0 +1,254 @@
+use super::util::{into_io_error, setsockopt_int};
+use nix::libc::{self, c_int};
+use nix::sys::socket::{setsockopt, sockopt};
+use std::io::Result as IoResult;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+pub(crate) const DEFAULT_BACKLOG: usize = 128;
+
+/// Socket options to apply to a listener or a connection. Options that are
+/// not set keep the system default:
+///
+/// ```ignore
+/// let options = SocketOptions::new()
+///   .reuse_address(true)
+///   .nodelay(true)
+///   .keepalive(true)
+///   .keepalive_idle(Duration::from_secs(60))
+///   .backlog(1024);
+/// let listener = TcpListener::<Socket>::bind_with("0.0.0.0:8001", &options)?;
+/// ```
+///
+/// When used to bind a listener, the connection options (`nodelay`,
+/// keepalive, `linger` and buffer sizes) are applied to every accepted
+/// connection as well.
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+  reuse_address: Option<bool>,
+  reuse_port: Option<bool>,
+  only_v6: Option<bool>,
+  defer_accept: Option<Duration>,
+  backlog: usize,
+  nodelay: Option<bool>,
+  keepalive: Option<bool>,
+  keepalive_idle: Option<Duration>,
+  keepalive_interval: Option<Duration>,
+  keepalive_count: Option<u32>,
+  linger: Option<Option<Duration>>,
+  recv_buffer_size: Option<usize>,
+  send_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+  fn default() -> Self {
+    SocketOptions {
+      reuse_address: None,
+      reuse_port: None,
+      only_v6: None,
+      defer_accept: None,
+      backlog: DEFAULT_BACKLOG,
+      nodelay: None,
+      keepalive: None,
+      keepalive_idle: None,
+      keepalive_interval: None,
+      keepalive_count: None,
+      linger: None,
+      recv_buffer_size: None,
+      send_buffer_size: None,
+    }
+  }
+}
+
+impl SocketOptions {
+  pub fn new() -> Self {
+    SocketOptions::default()
+  }
+
+  /// `SO_REUSEADDR`: allows binding while old connections to the address
+  /// are still in TIME_WAIT, so a restarted server can bind immediately.
+  pub fn reuse_address(mut self, reuse: bool) -> Self {
+    self.reuse_address = Some(reuse);
+    self
+  }
+
+  /// `SO_REUSEPORT`: lets several sockets bind the same address, with the
+  /// kernel spreading connections between them.
+  pub fn reuse_port(mut self, reuse: bool) -> Self {
+    self.reuse_port = Some(reuse);
+    self
+  }
+
+  /// `IPV6_V6ONLY`, only applied to IPv6 sockets.
+  pub fn only_v6(mut self, only_v6: bool) -> Self {
+    self.only_v6 = Some(only_v6);
+    self
+  }
+
+  /// `TCP_DEFER_ACCEPT`: only wake the listener once a connection has sent
+  /// data, waiting at most `timeout`.
+  pub fn defer_accept(mut self, timeout: Duration) -> Self {
+    self.defer_accept = Some(timeout);
+    self
+  }
+
+  /// Length of the queue of connections waiting to be accepted. Defaults
+  /// to 128.
+  pub fn backlog(mut self, backlog: usize) -> Self {
+    self.backlog = backlog;
+    self
+  }
+
+  /// `TCP_NODELAY`: sends small writes straight away instead of waiting to
+  /// coalesce them (Nagle's algorithm).
+  pub fn nodelay(mut self, nodelay: bool) -> Self {
+    self.nodelay = Some(nodelay);
+    self
+  }
+
+  /// `SO_KEEPALIVE`: probes idle connections to detect dead peers.
+  pub fn keepalive(mut self, keepalive: bool) -> Self {
+    self.keepalive = Some(keepalive);
+    self
+  }
+
+  /// `TCP_KEEPIDLE`: how long a connection is idle before the first probe.
+  pub fn keepalive_idle(mut self, idle: Duration) -> Self {
+    self.keepalive_idle = Some(idle);
+    self
+  }
+
+  /// `TCP_KEEPINTVL`: time between unanswered probes.
+  pub fn keepalive_interval(mut self, interval: Duration) -> Self {
+    self.keepalive_interval = Some(interval);
+    self
+  }
+
+  /// `TCP_KEEPCNT`: unanswered probes before the connection is dropped.
+  pub fn keepalive_count(mut self, count: u32) -> Self {
+    self.keepalive_count = Some(count);
+    self
+  }
+
+  /// `SO_LINGER`: with `Some(timeout)`, closing blocks until unsent data
+  /// is delivered or `timeout` passes; `Some(0s)` resets the connection on
+  /// close. `None` restores the default of closing in the background.
+  pub fn linger(mut self, linger: Option<Duration>) -> Self {
+    self.linger = Some(linger);
+    self
+  }
+
+  /// `SO_RCVBUF`, in bytes. The kernel may double or cap it.
+  pub fn recv_buffer_size(mut self, size: usize) -> Self {
+    self.recv_buffer_size = Some(size);
+    self
+  }
+
+  /// `SO_SNDBUF`, in bytes. The kernel may double or cap it.
+  pub fn send_buffer_size(mut self, size: usize) -> Self {
+    self.send_buffer_size = Some(size);
+    self
+  }
+
+  pub(crate) fn listen_backlog(&self) -> usize {
+    self.backlog
+  }
+
+  /// The `only_v6` setting, which sockets apply through
+  /// `SocketLike::set_only_v6` rather than `apply`.
+  pub(crate) fn ipv6_only(&self) -> Option<bool> {
+    self.only_v6
+  }
+
+  /// The options that concern individual connections rather than
+  /// listening.
+  pub(crate) fn for_connections(&self) -> SocketOptions {
+    SocketOptions {
+      reuse_address: None,
+      reuse_port: None,
+      only_v6: None,
+      defer_accept: None,
+      ..self.clone()
+    }
+  }
+
+  /// Whether applying these options would change anything.
+  pub(crate) fn is_empty(&self) -> bool {
+    self.reuse_address.is_none()
+      && self.reuse_port.is_none()
+      && self.only_v6.is_none()
+      && self.defer_accept.is_none()
+      && self.nodelay.is_none()
+      && self.keepalive.is_none()
+      && self.keepalive_idle.is_none()
+      && self.keepalive_interval.is_none()
+      && self.keepalive_count.is_none()
+      && self.linger.is_none()
+      && self.recv_buffer_size.is_none()
+      && self.send_buffer_size.is_none()
+  }
+
+  /// Same options, but without the IPv6 specific ones.
+  pub(crate) fn for_ipv4(&self) -> SocketOptions {
+    SocketOptions {
+      only_v6: None,
+      ..self.clone()
+    }
+  }
+
+  /// Sets every option that was configured on `fd`.
+  pub(crate) fn apply(&self, fd: RawFd) -> IoResult<()> {
+    if let Some(reuse) = self.reuse_address {
+      setsockopt(fd, sockopt::ReuseAddr, &reuse).map_err(into_io_error)?;
+    }
+    if let Some(reuse) = self.reuse_port {
+      setsockopt(fd, sockopt::ReusePort, &reuse).map_err(into_io_error)?;
+    }
+    if let Some(timeout) = self.defer_accept {
+      let secs = seconds(timeout);
+      setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT, secs)?;
+    }
+    if let Some(nodelay) = self.nodelay {
+      setsockopt(fd, sockopt::TcpNoDelay, &nodelay).map_err(into_io_error)?;
+    }
+    if let Some(keepalive) = self.keepalive {
+      setsockopt(fd, sockopt::KeepAlive, &keepalive).map_err(into_io_error)?;
+    }
+    if let Some(idle) = self.keepalive_idle {
+      setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, seconds(idle))?;
+    }
+    if let Some(interval) = self.keepalive_interval {
+      setsockopt_int(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPINTVL,
+        seconds(interval),
+      )?;
+    }
+    if let Some(count) = self.keepalive_count {
+      let count = count.min(c_int::MAX as u32) as c_int;
+      setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count)?;
+    }
+    if let Some(linger) = self.linger {
+      let linger = libc::linger {
+        l_onoff: linger.is_some() as c_int,
+        l_linger: linger.map(seconds).unwrap_or(0),
+      };
+      setsockopt(fd, sockopt::Linger, &linger).map_err(into_io_error)?;
+    }
+    if let Some(size) = self.recv_buffer_size {
+      setsockopt(fd, sockopt::RcvBuf, &size).map_err(into_io_error)?;
+    }
+    if let Some(size) = self.send_buffer_size {
+      setsockopt(fd, sockopt::SndBuf, &size).map_err(into_io_error)?;
+    }
+    Ok(())
+  }
+}
+
+/// Socket options take whole seconds; round up so a sub-second timeout
+/// does not turn into "disabled".
+fn seconds(duration: Duration) -> c_int {
+  let secs = duration.as_secs() + (duration.subsec_nanos() > 0) as u64;
+  secs.min(c_int::MAX as u64) as c_int
+}
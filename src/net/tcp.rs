@@ -1,44 +1,139 @@
-use super::util::into_io_error;
+use super::options::SocketOptions;
+use super::util::{
+  check_timeout, connect_timeout, getsockopt_int, into_io_error, is_nonblocking, set_nonblocking,
+  setsockopt_int, timeout_value,
+};
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::sendfile::sendfile;
 use nix::sys::socket::{
-  accept, bind, getpeername, getsockname, listen, socket, AddressFamily, InetAddr, IpAddr,
-  SockAddr, SockFlag, SockProtocol, SockType,
+  self, accept4, bind, connect, getpeername, getsockname, listen, setsockopt, socket, sockopt,
+  AddressFamily, InetAddr, SockAddr, SockFlag, SockProtocol, SockType,
 };
 use nix::unistd::{close, read, write};
+use std::fs::File;
 use std::io::{Error, ErrorKind, Read, Result as IoResult, Write};
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Most bytes copied per call by the `send_file` fallback.
+const SEND_FILE_FALLBACK_CHUNK: usize = 64 * 1024;
 
 pub trait SocketLike {
-  fn new() -> IoResult<Box<Self>>;
-  fn accept(&self) -> IoResult<Box<Self>>;
-  fn get_peer_name(&self) -> IoResult<SocketAddr>;
-  fn get_sock_name(&self) -> IoResult<SocketAddr>;
-  fn bind(&mut self, addr: SocketAddr) -> IoResult<()>;
+  /// The kind of address the socket binds to, e.g. `SocketAddr`.
+  type Addr;
+
+  /// Creates a stream socket for `family`, e.g. `AddressFamily::Inet`,
+  /// `AddressFamily::Inet6` or `AddressFamily::Unix`.
+  fn new(family: AddressFamily) -> IoResult<Box<Self>>;
+  /// Accepts a connection. `flags` may ask for `SOCK_NONBLOCK`; accepted
+  /// sockets are always `SOCK_CLOEXEC` so they do not leak into child
+  /// processes.
+  fn accept(&self, flags: SockFlag) -> IoResult<Box<Self>>;
+  fn get_peer_name(&self) -> IoResult<Self::Addr>;
+  fn get_sock_name(&self) -> IoResult<Self::Addr>;
+  fn bind(&mut self, addr: Self::Addr) -> IoResult<()>;
+  /// Connects to `addr`, failing with `ErrorKind::TimedOut` if that takes
+  /// longer than `timeout`. `None` waits as long as the system does.
+  fn connect(&mut self, addr: Self::Addr, timeout: Option<Duration>) -> IoResult<()>;
   fn listen(&self, backlog: usize) -> IoResult<()>;
+  /// Shuts down the reading half, the writing half or both halves of a
+  /// connection.
+  fn shutdown(&self, how: Shutdown) -> IoResult<()>;
+  /// Creates a new handle for the same underlying socket.
+  fn try_clone(&self) -> IoResult<Box<Self>>;
   fn close(&self) -> IoResult<()>;
   fn read(&self, buf: &mut [u8]) -> IoResult<usize>;
   fn write(&self, buf: &[u8]) -> IoResult<usize>;
+  /// Writes up to `count` bytes of `file` starting at `offset`, returning
+  /// how many were sent; 0 means the file ended. Sockets backed by a file
+  /// descriptor use `sendfile(2)`, others fall back to reading the file
+  /// and writing what was read.
+  fn send_file(&self, file: &File, offset: u64, count: usize) -> IoResult<usize> {
+    let mut buf = vec![0; count.min(SEND_FILE_FALLBACK_CHUNK)];
+    let read = file.read_at(&mut buf, offset)?;
+    if read == 0 {
+      return Ok(0);
+    }
+    self.write(&buf[..read])
+  }
+  /// Waits up to `timeout` for the socket to become readable. Returns `false`
+  /// if the timeout elapsed first.
+  fn poll_read(&self, timeout: Duration) -> IoResult<bool>;
+  /// Controls `IPV6_V6ONLY` on an IPv6 socket. When disabled, a socket bound
+  /// to `[::]` accepts IPv4 connections too, with v4-mapped peer addresses.
+  /// Must be set before binding.
+  fn set_only_v6(&self, only_v6: bool) -> IoResult<()>;
+  fn only_v6(&self) -> IoResult<bool>;
+  /// Applies every option set in `options`. Options affecting binding, like
+  /// `reuse_address` or `only_v6`, must be set before `bind`.
+  fn set_options(&self, options: &SocketOptions) -> IoResult<()>;
+  /// Makes reads that wait longer than `timeout` for data fail with
+  /// `ErrorKind::TimedOut`. `None` waits forever.
+  fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()>;
+  /// Makes writes that wait longer than `timeout` for buffer space fail with
+  /// `ErrorKind::TimedOut`. `None` waits forever.
+  fn set_write_timeout(&self, timeout: Option<Duration>) -> IoResult<()>;
+  /// Switches the socket in or out of non-blocking mode, in which reads,
+  /// writes and accepts that cannot complete immediately fail with
+  /// `ErrorKind::WouldBlock`.
+  fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()>;
 }
 
 // ----- Begin: Socket ------
 
-pub struct Socket(i32);
+/// A socket file descriptor, and whether it is in non-blocking mode so that
+/// failed reads and writes need not ask the kernel.
+pub struct Socket(i32, AtomicBool);
+
+impl Socket {
+  fn from_fd(fd: RawFd, nonblocking: bool) -> Socket {
+    Socket(fd, AtomicBool::new(nonblocking))
+  }
+
+  fn is_nonblocking(&self) -> bool {
+    self.1.load(Ordering::Relaxed)
+  }
+}
+
+impl AsRawFd for Socket {
+  fn as_raw_fd(&self) -> RawFd {
+    self.0
+  }
+}
+
+impl FromRawFd for Socket {
+  /// Takes ownership of `fd`, which must be an open socket; it is closed
+  /// when the `Socket` is dropped.
+  unsafe fn from_raw_fd(fd: RawFd) -> Socket {
+    Socket::from_fd(fd, is_nonblocking(fd).unwrap_or(false))
+  }
+}
 
 impl SocketLike for Socket {
-  fn new() -> IoResult<Box<Socket>> {
-    match socket(
-      AddressFamily::Inet,
-      SockType::Stream,
-      SockFlag::empty(),
-      SockProtocol::Tcp,
-    ) {
-      Ok(raw_fd) => Ok(Box::new(Socket(raw_fd))),
+  type Addr = SocketAddr;
+
+  fn new(family: AddressFamily) -> IoResult<Box<Socket>> {
+    let protocol = match family {
+      AddressFamily::Unix => None,
+      _ => Some(SockProtocol::Tcp),
+    };
+    match socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, protocol) {
+      Ok(raw_fd) => Ok(Box::new(Socket::from_fd(raw_fd, false))),
       Err(err) => Err(into_io_error(err)),
     }
   }
 
-  fn accept(&self) -> IoResult<Box<Socket>> {
-    match accept(self.0) {
-      Ok(raw_fd) => Ok(Box::new(Socket(raw_fd))),
+  fn accept(&self, flags: SockFlag) -> IoResult<Box<Socket>> {
+    match accept4(self.0, flags | SockFlag::SOCK_CLOEXEC) {
+      Ok(raw_fd) => Ok(Box::new(Socket::from_fd(
+        raw_fd,
+        flags.contains(SockFlag::SOCK_NONBLOCK),
+      ))),
       Err(err) => Err(into_io_error(err)),
     }
   }
@@ -60,24 +155,95 @@ impl SocketLike for Socket {
   }
 
   fn bind(&mut self, addr: SocketAddr) -> IoResult<()> {
-    let address = SockAddr::new_inet(InetAddr::new(IpAddr::from_std(&addr.ip()), addr.port()));
+    let address = SockAddr::new_inet(InetAddr::from_std(&addr));
     bind(self.0, &address).map_err(into_io_error)
   }
 
+  fn connect(&mut self, addr: SocketAddr, timeout: Option<Duration>) -> IoResult<()> {
+    let address = SockAddr::new_inet(InetAddr::from_std(&addr));
+    connect_timeout(self.0, timeout, || {
+      connect(self.0, &address).map_err(into_io_error)
+    })
+  }
+
   fn listen(&self, backlog: usize) -> IoResult<()> {
     listen(self.0, backlog).map_err(into_io_error)
   }
 
+  fn shutdown(&self, how: Shutdown) -> IoResult<()> {
+    let how = match how {
+      Shutdown::Read => socket::Shutdown::Read,
+      Shutdown::Write => socket::Shutdown::Write,
+      Shutdown::Both => socket::Shutdown::Both,
+    };
+    socket::shutdown(self.0, how).map_err(into_io_error)
+  }
+
+  fn try_clone(&self) -> IoResult<Box<Socket>> {
+    // The duplicate shares the file status flags, O_NONBLOCK included.
+    match fcntl(self.0, FcntlArg::F_DUPFD_CLOEXEC(0)) {
+      Ok(raw_fd) => Ok(Box::new(Socket::from_fd(raw_fd, self.is_nonblocking()))),
+      Err(err) => Err(into_io_error(err)),
+    }
+  }
+
   fn close(&self) -> IoResult<()> {
     close(self.0).map_err(into_io_error)
   }
 
   fn read(&self, buf: &mut [u8]) -> IoResult<usize> {
-    read(self.0, buf).map_err(into_io_error)
+    read(self.0, buf).map_err(|err| check_timeout(self.is_nonblocking(), into_io_error(err)))
   }
 
   fn write(&self, buf: &[u8]) -> IoResult<usize> {
-    write(self.0, buf).map_err(into_io_error)
+    write(self.0, buf).map_err(|err| check_timeout(self.is_nonblocking(), into_io_error(err)))
+  }
+
+  fn send_file(&self, file: &File, offset: u64, count: usize) -> IoResult<usize> {
+    let mut offset = offset as libc::off_t;
+    sendfile(self.0, file.as_raw_fd(), Some(&mut offset), count)
+      .map_err(|err| check_timeout(self.is_nonblocking(), into_io_error(err)))
+  }
+
+  fn poll_read(&self, timeout: Duration) -> IoResult<bool> {
+    let mut fds = [PollFd::new(self.0, PollFlags::POLLIN)];
+    let millis = timeout.as_millis().min(i32::MAX as u128) as i32;
+    let ready = poll(&mut fds, millis).map_err(into_io_error)?;
+    Ok(ready > 0)
+  }
+
+  fn set_only_v6(&self, only_v6: bool) -> IoResult<()> {
+    setsockopt_int(
+      self.0,
+      libc::IPPROTO_IPV6,
+      libc::IPV6_V6ONLY,
+      only_v6 as libc::c_int,
+    )
+  }
+
+  fn only_v6(&self) -> IoResult<bool> {
+    getsockopt_int(self.0, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY).map(|value| value != 0)
+  }
+
+  fn set_options(&self, options: &SocketOptions) -> IoResult<()> {
+    if let Some(only_v6) = options.ipv6_only() {
+      self.set_only_v6(only_v6)?;
+    }
+    options.apply(self.0)
+  }
+
+  fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+    setsockopt(self.0, sockopt::ReceiveTimeout, &timeout_value(timeout)?).map_err(into_io_error)
+  }
+
+  fn set_write_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+    setsockopt(self.0, sockopt::SendTimeout, &timeout_value(timeout)?).map_err(into_io_error)
+  }
+
+  fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+    set_nonblocking(self.0, nonblocking)?;
+    self.1.store(nonblocking, Ordering::Relaxed);
+    Ok(())
   }
 }
 
@@ -95,6 +261,115 @@ pub struct TcpStream<T: SocketLike> {
   inner: T,
 }
 
+impl<T: SocketLike> TcpStream<T> {
+  /// See `SocketLike::set_read_timeout`.
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+    self.inner.set_read_timeout(timeout)
+  }
+
+  /// See `SocketLike::set_write_timeout`.
+  pub fn set_write_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+    self.inner.set_write_timeout(timeout)
+  }
+
+  pub(crate) fn inner(&self) -> &T {
+    &self.inner
+  }
+
+  /// The address of the remote end of the connection.
+  pub fn peer_addr(&self) -> IoResult<T::Addr> {
+    self.inner.get_peer_name()
+  }
+
+  /// The address of the local end of the connection.
+  pub fn local_addr(&self) -> IoResult<T::Addr> {
+    self.inner.get_sock_name()
+  }
+
+  /// Shuts down the reading half, the writing half or both halves of the
+  /// connection. Shutting down writing sends the peer an end of file while
+  /// replies can still be read.
+  pub fn shutdown(&self, how: Shutdown) -> IoResult<()> {
+    self.inner.shutdown(how)
+  }
+
+  /// A second handle to the same connection, e.g. to read and write from
+  /// different threads.
+  pub fn try_clone(&self) -> IoResult<TcpStream<T>> {
+    Ok(TcpStream {
+      inner: *self.inner.try_clone()?,
+    })
+  }
+
+  /// Blocks until there is data to read (or the peer hung up) or until
+  /// `timeout` elapses, in which case `false` is returned.
+  pub fn wait_readable(&self, timeout: Duration) -> IoResult<bool> {
+    self.inner.poll_read(timeout)
+  }
+
+  /// See `SocketLike::set_nonblocking`.
+  pub fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+    self.inner.set_nonblocking(nonblocking)
+  }
+
+  /// See `SocketLike::send_file`.
+  pub fn send_file(&self, file: &File, offset: u64, count: usize) -> IoResult<usize> {
+    self.inner.send_file(file, offset, count)
+  }
+}
+
+impl<T: SocketLike<Addr = SocketAddr>> TcpStream<T> {
+  /// Opens a connection to the first of the resolved addresses that
+  /// accepts one.
+  pub fn connect(addr: impl ToSocketAddrs) -> IoResult<TcpStream<T>> {
+    Self::connect_with(addr, None)
+  }
+
+  /// Like `connect`, but gives up on each address after `timeout` with
+  /// `ErrorKind::TimedOut`.
+  pub fn connect_timeout(addr: impl ToSocketAddrs, timeout: Duration) -> IoResult<TcpStream<T>> {
+    Self::connect_with(addr, Some(timeout))
+  }
+
+  fn connect_with(addr: impl ToSocketAddrs, timeout: Option<Duration>) -> IoResult<TcpStream<T>> {
+    let mut last_err = None;
+
+    for addr in addr.to_socket_addrs()? {
+      let family = if addr.is_ipv4() {
+        AddressFamily::Inet
+      } else {
+        AddressFamily::Inet6
+      };
+      let result = T::new(family).and_then(|mut socket| {
+        socket.connect(addr, timeout)?;
+        Ok(TcpStream { inner: *socket })
+      });
+      match result {
+        Ok(stream) => return Ok(stream),
+        Err(err) => last_err = Some(err),
+      }
+    }
+    Err(last_err.unwrap_or_else(|| {
+      Error::new(
+        ErrorKind::InvalidInput,
+        "could not resolve to any addresses",
+      )
+    }))
+  }
+}
+
+impl<T: SocketLike + AsRawFd> TcpStream<T> {
+  pub fn set_options(&self, options: &SocketOptions) -> IoResult<()> {
+    self.inner.set_options(options)
+  }
+}
+
+impl<T: SocketLike + AsRawFd> AsRawFd for TcpStream<T> {
+  fn as_raw_fd(&self) -> RawFd {
+    self.inner.as_raw_fd()
+  }
+}
+
 impl<T: SocketLike> Read for TcpStream<T> {
   fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
     self.inner.read(buf)
@@ -133,36 +408,124 @@ impl<T: SocketLike> Write for &TcpStream<T> {
 
 pub struct TcpListener<T: SocketLike> {
   inner: T,
+  /// Options applied to every accepted connection.
+  connection_options: Option<SocketOptions>,
 }
 
 impl<T: SocketLike> TcpListener<T> {
-  pub fn bind(ip: impl ToSocketAddrs) -> IoResult<TcpListener<T>> {
-    let ip_addresses = ip.to_socket_addrs()?;
-
-    for addr in ip_addresses {
-      if addr.is_ipv4() {
-        let mut sock = *T::new()?;
-        sock.bind(addr)?;
-        sock.listen(128)?;
-        return Ok(TcpListener { inner: sock });
-      }
+  pub(crate) fn from_inner(inner: T) -> Self {
+    TcpListener {
+      inner,
+      connection_options: None,
     }
-    Err(Error::from(ErrorKind::Other))
   }
 
-  pub fn accept(&self) -> IoResult<(TcpStream<T>, SocketAddr)> {
-    let new_socket = *self.inner.accept()?;
+  pub fn accept(&self) -> IoResult<(TcpStream<T>, T::Addr)> {
+    self.accept_with(SockFlag::empty())
+  }
+
+  /// Like `accept`, with extra `accept4(2)` flags for the new connection.
+  /// `SockFlag::SOCK_NONBLOCK` hands it out already in non-blocking mode.
+  pub fn accept_with(&self, flags: SockFlag) -> IoResult<(TcpStream<T>, T::Addr)> {
+    let new_socket = *self.inner.accept(flags)?;
+    if let Some(options) = &self.connection_options {
+      new_socket.set_options(options)?;
+    }
     let socket_addr = new_socket.get_peer_name()?;
     Ok((TcpStream { inner: new_socket }, socket_addr))
   }
 
-  pub fn incoming(&self) -> Incoming<T> {
+  /// Applies `options` to the listening socket. The connection options
+  /// among them are applied to connections accepted from now on as well.
+  pub fn set_options(&mut self, options: &SocketOptions) -> IoResult<()> {
+    self.inner.set_options(options)?;
+    let connection_options = options.for_connections();
+    if !connection_options.is_empty() {
+      self.connection_options = Some(connection_options);
+    }
+    Ok(())
+  }
+
+  /// Waits up to `timeout` for a connection to be ready to accept.
+  pub fn poll_accept(&self, timeout: Duration) -> IoResult<bool> {
+    self.inner.poll_read(timeout)
+  }
+
+  /// Iterates over accepted connections. On a non-blocking listener it
+  /// yields `ErrorKind::WouldBlock` errors instead of waiting, so it can be
+  /// drained whenever an external poller reports the listener readable.
+  pub fn incoming(&self) -> Incoming<'_, T> {
     Incoming { listener: self }
   }
 
-  pub fn local_addr(&self) -> IoResult<SocketAddr> {
+  pub fn local_addr(&self) -> IoResult<T::Addr> {
     self.inner.get_sock_name()
   }
+
+  /// See `SocketLike::set_nonblocking`. Only affects accepting; accepted
+  /// connections stay blocking unless asked otherwise via `accept_with`.
+  pub fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+    self.inner.set_nonblocking(nonblocking)
+  }
+}
+
+impl<T: SocketLike<Addr = SocketAddr>> TcpListener<T> {
+  /// Binds to the first of the resolved addresses that works, IPv4 or IPv6,
+  /// with `SO_REUSEADDR` set so a restarted server can bind right away.
+  /// Whether a socket bound to `[::]` also accepts IPv4 connections is left
+  /// to the system default; use `bind_v6` to choose explicitly.
+  pub fn bind(ip: impl ToSocketAddrs) -> IoResult<TcpListener<T>> {
+    Self::bind_with(ip, &SocketOptions::new().reuse_address(true))
+  }
+
+  /// Like `bind`, but sets `IPV6_V6ONLY` to `only_v6` on IPv6 sockets.
+  /// `bind_v6("[::]:8001", false)` listens on both IPv6 and IPv4.
+  pub fn bind_v6(ip: impl ToSocketAddrs, only_v6: bool) -> IoResult<TcpListener<T>> {
+    let options = SocketOptions::new().reuse_address(true).only_v6(only_v6);
+    Self::bind_with(ip, &options)
+  }
+
+  /// Like `bind`, but with exactly the given `options` instead of the
+  /// defaults. See `SocketOptions`.
+  pub fn bind_with(ip: impl ToSocketAddrs, options: &SocketOptions) -> IoResult<TcpListener<T>> {
+    let mut last_err = None;
+
+    for addr in ip.to_socket_addrs()? {
+      let result = if addr.is_ipv4() {
+        Self::bind_one(addr, AddressFamily::Inet, &options.for_ipv4())
+      } else {
+        Self::bind_one(addr, AddressFamily::Inet6, options)
+      };
+      match result {
+        Ok(listener) => return Ok(listener),
+        Err(err) => last_err = Some(err),
+      }
+    }
+    Err(last_err.unwrap_or_else(|| {
+      Error::new(
+        ErrorKind::InvalidInput,
+        "could not resolve to any addresses",
+      )
+    }))
+  }
+
+  fn bind_one(
+    addr: SocketAddr,
+    family: AddressFamily,
+    options: &SocketOptions,
+  ) -> IoResult<TcpListener<T>> {
+    let mut listener = TcpListener::from_inner(*T::new(family)?);
+    listener.set_options(options)?;
+    listener.inner.bind(addr)?;
+    listener.inner.listen(options.listen_backlog())?;
+    Ok(listener)
+  }
+}
+
+impl<T: SocketLike + AsRawFd> AsRawFd for TcpListener<T> {
+  fn as_raw_fd(&self) -> RawFd {
+    self.inner.as_raw_fd()
+  }
 }
 
 // ----- End TcpListener ------
@@ -183,86 +546,192 @@ impl<'a, T: SocketLike> Iterator for Incoming<'a, T> {
 mod tests {
   // Note this useful idiom: importing names from outer (for mod tests) scope.
   use super::*;
+  use crate::net::memory::MemorySocket;
+  use nix::libc;
 
-  static mut DATA: Vec<u8> = Vec::new();
+  const NEW_ACCPT_ADDR: &str = "127.0.0.1:4000";
 
-  struct GoodSocket {
-    address: SocketAddr,
+  #[test]
+  fn test_binds_tcp_listener_successfully() {
+    let listener = TcpListener::<MemorySocket>::bind("127.0.0.1:1000");
+    assert!(listener.is_ok());
   }
 
-  const NEW_SOCK_ADDR: &str = "127.0.0.1:3000";
-  const NEW_ACCPT_ADDR: &str = "127.0.0.1:4000";
+  #[test]
+  fn test_accepts_incoming_connections_successfully() {
+    let socket = MemorySocket::new();
+    let remote = socket.peer();
+    let listener = TcpListener::from_memory(socket).unwrap();
+    remote.connect(MemorySocket::new().peer_addr(NEW_ACCPT_ADDR.parse().unwrap()));
+    // unwrap is okay here because we want to fail if it it's Err
+    let result = listener.accept();
+    assert!(result.is_ok());
+    let (_, new_socket_addr) = result.unwrap();
+    assert_eq!(
+      new_socket_addr,
+      NEW_ACCPT_ADDR.to_socket_addrs().unwrap().next().unwrap()
+    )
+  }
 
-  impl SocketLike for GoodSocket {
-    fn new() -> IoResult<Box<GoodSocket>> {
-      // Okay because we know it's a valid socket address
-      let address = NEW_SOCK_ADDR.to_socket_addrs().unwrap().next().unwrap();
-      Ok(Box::new(GoodSocket { address }))
-    }
+  #[test]
+  fn connects_through_the_socket_trait() {
+    let stream = TcpStream::<MemorySocket>::connect("127.0.0.1:5000").unwrap();
+    assert_eq!(stream.peer_addr().unwrap().port(), 5000);
+    assert_eq!(
+      stream.try_clone().unwrap().peer_addr().unwrap().port(),
+      5000
+    );
+  }
 
-    fn accept(&self) -> IoResult<Box<GoodSocket>> {
-      let new_address = NEW_ACCPT_ADDR.to_socket_addrs().unwrap().next().unwrap();
-      Ok(Box::new(GoodSocket {
-        address: new_address,
-      }))
-    }
+  #[test]
+  fn half_closes_outbound_connections() {
+    let listener = TcpListener::<Socket>::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut client = TcpStream::<Socket>::connect_timeout(addr, Duration::from_secs(5)).unwrap();
+    let (mut server, peer) = listener.accept().unwrap();
+    assert_eq!(client.local_addr().unwrap(), peer);
+    assert_eq!(client.peer_addr().unwrap(), addr);
+
+    client.write_all(b"ping").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut request = Vec::new();
+    server.read_to_end(&mut request).unwrap();
+    assert_eq!(request, b"ping");
+
+    // The reading half is still open, through either handle.
+    server.write_all(b"pong").unwrap();
+    drop(server);
+    let mut response = Vec::new();
+    client
+      .try_clone()
+      .unwrap()
+      .read_to_end(&mut response)
+      .unwrap();
+    assert_eq!(response, b"pong");
+  }
 
-    fn get_peer_name(&self) -> IoResult<SocketAddr> {
-      Ok(self.address)
-    }
+  #[test]
+  fn refused_connections_fail() {
+    let addr = TcpListener::<Socket>::bind("127.0.0.1:0")
+      .unwrap()
+      .local_addr()
+      .unwrap();
+    let err = TcpStream::<Socket>::connect_timeout(addr, Duration::from_secs(5))
+      .err()
+      .unwrap();
+    assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    let err = TcpStream::<Socket>::connect(addr).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+  }
 
-    fn get_sock_name(&self) -> IoResult<SocketAddr> {
-      Ok(self.address)
-    }
+  #[test]
+  fn accepts_cloexec_and_nonblocking() {
+    use nix::fcntl::FdFlag;
+
+    let listener = TcpListener::<Socket>::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let err = listener.incoming().next().unwrap().err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+    let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    assert!(listener.poll_accept(Duration::from_secs(5)).unwrap());
+    let (stream, _) = listener.accept_with(SockFlag::SOCK_NONBLOCK).unwrap();
+    let fd_flags = fcntl(stream.as_raw_fd(), FcntlArg::F_GETFD).unwrap();
+    assert!(FdFlag::from_bits_truncate(fd_flags).contains(FdFlag::FD_CLOEXEC));
+    assert!(is_nonblocking(stream.as_raw_fd()).unwrap());
+  }
 
-    fn bind(&mut self, addr: SocketAddr) -> IoResult<()> {
-      self.address = addr;
-      Ok(())
-    }
+  #[test]
+  fn binds_ipv6() {
+    let listener = TcpListener::<Socket>::bind("[::1]:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    assert!(addr.is_ipv6());
+
+    std::net::TcpStream::connect(addr).unwrap();
+    let (_, peer) = listener.accept().unwrap();
+    assert!(peer.is_ipv6());
+  }
 
-    fn listen(&self, _backlog: usize) -> IoResult<()> {
-      Ok(())
-    }
+  fn int_option(fd: &impl AsRawFd, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+    getsockopt_int(fd.as_raw_fd(), level, name).unwrap()
+  }
 
-    fn close(&self) -> IoResult<()> {
-      Ok(())
-    }
+  #[test]
+  fn dual_stack_accepts_ipv4() {
+    let listener = TcpListener::<Socket>::bind_v6("[::]:0", false).unwrap();
+    assert!(!listener.inner.only_v6().unwrap());
 
-    fn read(&self, buf: &mut [u8]) -> IoResult<usize> {
-      let mut count = 0;
-      for val in buf {
-        *val = 1;
-        count += 1;
-      }
-      Ok(count)
-    }
+    let port = listener.local_addr().unwrap().port();
+    std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let (_, peer) = listener.accept().unwrap();
+    assert_eq!(peer.ip().to_string(), "::ffff:127.0.0.1");
 
-    fn write(&self, buf: &[u8]) -> IoResult<usize> {
-      let mut count = 0;
-      for val in buf {
-        unsafe { DATA.push(*val) };
-        count += 1;
-      }
-      Ok(count)
-    }
+    let listener = TcpListener::<Socket>::bind_v6("[::]:0", true).unwrap();
+    assert!(listener.inner.only_v6().unwrap());
   }
 
   #[test]
-  fn test_binds_tcp_listener_successfully() {
-    let listener = TcpListener::<GoodSocket>::bind("127.0.0.1:1000");
-    assert!(listener.is_ok());
+  fn applies_socket_options() {
+    let options = SocketOptions::new()
+      .reuse_address(true)
+      .reuse_port(true)
+      .defer_accept(Duration::from_secs(5))
+      .nodelay(true)
+      .keepalive(true)
+      .keepalive_idle(Duration::from_secs(30))
+      .keepalive_interval(Duration::from_secs(10))
+      .keepalive_count(4)
+      .backlog(16);
+    let listener = TcpListener::<Socket>::bind_with("127.0.0.1:0", &options).unwrap();
+    assert_eq!(
+      int_option(&listener, libc::SOL_SOCKET, libc::SO_REUSEADDR),
+      1
+    );
+    assert_eq!(
+      int_option(&listener, libc::SOL_SOCKET, libc::SO_REUSEPORT),
+      1
+    );
+    assert!(int_option(&listener, libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT) > 0);
+
+    let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    // Deferred accept only completes once the client sent something.
+    client.write_all(b"hi").unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    assert_eq!(int_option(&stream, libc::IPPROTO_TCP, libc::TCP_NODELAY), 1);
+    assert_eq!(int_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+    assert_eq!(
+      int_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE),
+      30
+    );
+    assert_eq!(
+      int_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL),
+      10
+    );
+    assert_eq!(int_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 4);
+
+    stream
+      .set_options(&SocketOptions::new().linger(Some(Duration::from_secs(2))))
+      .unwrap();
   }
 
   #[test]
-  fn test_accepts_incoming_connections_successfully() {
-    // unwrap is okay here because we want to fail if it it's Err
-    let listener = TcpListener::<GoodSocket>::bind("127.0.0.1:1000").unwrap();
-    let result = listener.accept();
-    assert!(result.is_ok());
-    let (_, new_socket_addr) = result.unwrap();
-    assert_eq!(
-      new_socket_addr,
-      NEW_ACCPT_ADDR.to_socket_addrs().unwrap().next().unwrap()
-    )
+  fn read_timeout_surfaces_as_timed_out() {
+    let listener = TcpListener::<Socket>::bind("127.0.0.1:0").unwrap();
+    let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+
+    stream
+      .set_read_timeout(Some(Duration::from_millis(20)))
+      .unwrap();
+    let err = stream.read(&mut [0; 8]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+    stream.set_nonblocking(true).unwrap();
+    let err = stream.read(&mut [0; 8]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+    assert!(stream
+      .set_read_timeout(Some(Duration::from_secs(0)))
+      .is_err());
   }
 }
@@ -0,0 +1,483 @@
+use super::util::{check_timeout, into_io_error, set_nonblocking, setsockopt_raw, timeout_value};
+use nix::libc;
+use nix::sys::socket::{
+  self, bind, connect, getpeername, getsockname, recv, recvfrom, send, sendto, setsockopt, socket,
+  sockopt, AddressFamily, InetAddr, IpMembershipRequest, MsgFlags, SockAddr, SockFlag, SockType,
+};
+use nix::unistd::close;
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// The datagram counterpart of `SocketLike`, so code built on `UdpSocket`
+/// can be tested without the network.
+pub trait DatagramLike {
+  /// The kind of address the socket sends to, e.g. `SocketAddr`.
+  type Addr;
+
+  /// Creates a datagram socket for `family`, e.g. `AddressFamily::Inet` or
+  /// `AddressFamily::Inet6`.
+  fn new(family: AddressFamily) -> IoResult<Box<Self>>;
+  fn bind(&mut self, addr: Self::Addr) -> IoResult<()>;
+  /// Sets the default destination for `send` and only receives datagrams
+  /// from `addr` from now on.
+  fn connect(&mut self, addr: Self::Addr) -> IoResult<()>;
+  fn get_peer_name(&self) -> IoResult<Self::Addr>;
+  fn get_sock_name(&self) -> IoResult<Self::Addr>;
+  fn close(&self) -> IoResult<()>;
+  /// Sends one datagram to the connected address.
+  fn send(&self, buf: &[u8]) -> IoResult<usize>;
+  /// Receives one datagram from the connected address. Whatever does not
+  /// fit in `buf` is discarded.
+  fn recv(&self, buf: &mut [u8]) -> IoResult<usize>;
+  fn send_to(&self, buf: &[u8], addr: Self::Addr) -> IoResult<usize>;
+  fn recv_from(&self, buf: &mut [u8]) -> IoResult<(usize, Self::Addr)>;
+  /// Joins the IPv4 multicast group `group` on the interface with address
+  /// `interface`, or the default one for `Ipv4Addr::UNSPECIFIED`.
+  fn join_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> IoResult<()>;
+  fn leave_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> IoResult<()>;
+  /// Joins the IPv6 multicast group `group` on the interface with index
+  /// `interface`, or the default one for 0.
+  fn join_multicast_v6(&self, group: Ipv6Addr, interface: u32) -> IoResult<()>;
+  fn leave_multicast_v6(&self, group: Ipv6Addr, interface: u32) -> IoResult<()>;
+  /// See `SocketLike::set_read_timeout`.
+  fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()>;
+  /// See `SocketLike::set_write_timeout`.
+  fn set_write_timeout(&self, timeout: Option<Duration>) -> IoResult<()>;
+  /// See `SocketLike::set_nonblocking`.
+  fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()>;
+}
+
+// ----- Begin: DatagramSocket ------
+
+/// A datagram socket file descriptor, and whether it is in non-blocking
+/// mode.
+pub struct DatagramSocket(RawFd, AtomicBool);
+
+impl DatagramSocket {
+  fn is_nonblocking(&self) -> bool {
+    self.1.load(Ordering::Relaxed)
+  }
+}
+
+impl AsRawFd for DatagramSocket {
+  fn as_raw_fd(&self) -> RawFd {
+    self.0
+  }
+}
+
+fn to_std(addr: SockAddr) -> IoResult<SocketAddr> {
+  match addr {
+    SockAddr::Inet(iaddr) => Ok(iaddr.to_std()),
+    _ => Err(Error::from(ErrorKind::Other)),
+  }
+}
+
+fn ipv6_membership(fd: RawFd, name: libc::c_int, group: Ipv6Addr, interface: u32) -> IoResult<()> {
+  // nix always uses interface 0 for IPv6 groups, so build the request here.
+  let request = libc::ipv6_mreq {
+    ipv6mr_multiaddr: libc::in6_addr {
+      s6_addr: group.octets(),
+    },
+    ipv6mr_interface: interface,
+  };
+  setsockopt_raw(fd, libc::IPPROTO_IPV6, name, &request)
+}
+
+impl DatagramLike for DatagramSocket {
+  type Addr = SocketAddr;
+
+  fn new(family: AddressFamily) -> IoResult<Box<DatagramSocket>> {
+    match socket(family, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None) {
+      Ok(raw_fd) => Ok(Box::new(DatagramSocket(raw_fd, AtomicBool::new(false)))),
+      Err(err) => Err(into_io_error(err)),
+    }
+  }
+
+  fn bind(&mut self, addr: SocketAddr) -> IoResult<()> {
+    let address = SockAddr::new_inet(InetAddr::from_std(&addr));
+    bind(self.0, &address).map_err(into_io_error)
+  }
+
+  fn connect(&mut self, addr: SocketAddr) -> IoResult<()> {
+    let address = SockAddr::new_inet(InetAddr::from_std(&addr));
+    connect(self.0, &address).map_err(into_io_error)
+  }
+
+  fn get_peer_name(&self) -> IoResult<SocketAddr> {
+    to_std(getpeername(self.0).map_err(into_io_error)?)
+  }
+
+  fn get_sock_name(&self) -> IoResult<SocketAddr> {
+    to_std(getsockname(self.0).map_err(into_io_error)?)
+  }
+
+  fn close(&self) -> IoResult<()> {
+    close(self.0).map_err(into_io_error)
+  }
+
+  fn send(&self, buf: &[u8]) -> IoResult<usize> {
+    send(self.0, buf, MsgFlags::empty())
+      .map_err(|err| check_timeout(self.is_nonblocking(), into_io_error(err)))
+  }
+
+  fn recv(&self, buf: &mut [u8]) -> IoResult<usize> {
+    recv(self.0, buf, MsgFlags::empty())
+      .map_err(|err| check_timeout(self.is_nonblocking(), into_io_error(err)))
+  }
+
+  fn send_to(&self, buf: &[u8], addr: SocketAddr) -> IoResult<usize> {
+    let address = SockAddr::new_inet(InetAddr::from_std(&addr));
+    sendto(self.0, buf, &address, MsgFlags::empty())
+      .map_err(|err| check_timeout(self.is_nonblocking(), into_io_error(err)))
+  }
+
+  fn recv_from(&self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+    let (read, addr) = recvfrom(self.0, buf)
+      .map_err(|err| check_timeout(self.is_nonblocking(), into_io_error(err)))?;
+    let addr = addr.ok_or_else(|| Error::other("datagram without a sender"))?;
+    Ok((read, to_std(addr)?))
+  }
+
+  fn join_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> IoResult<()> {
+    let request = IpMembershipRequest::new(
+      socket::Ipv4Addr::from_std(&group),
+      Some(socket::Ipv4Addr::from_std(&interface)),
+    );
+    setsockopt(self.0, sockopt::IpAddMembership, &request).map_err(into_io_error)
+  }
+
+  fn leave_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> IoResult<()> {
+    let request = IpMembershipRequest::new(
+      socket::Ipv4Addr::from_std(&group),
+      Some(socket::Ipv4Addr::from_std(&interface)),
+    );
+    setsockopt(self.0, sockopt::IpDropMembership, &request).map_err(into_io_error)
+  }
+
+  fn join_multicast_v6(&self, group: Ipv6Addr, interface: u32) -> IoResult<()> {
+    ipv6_membership(self.0, libc::IPV6_ADD_MEMBERSHIP, group, interface)
+  }
+
+  fn leave_multicast_v6(&self, group: Ipv6Addr, interface: u32) -> IoResult<()> {
+    ipv6_membership(self.0, libc::IPV6_DROP_MEMBERSHIP, group, interface)
+  }
+
+  fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+    setsockopt(self.0, sockopt::ReceiveTimeout, &timeout_value(timeout)?).map_err(into_io_error)
+  }
+
+  fn set_write_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+    setsockopt(self.0, sockopt::SendTimeout, &timeout_value(timeout)?).map_err(into_io_error)
+  }
+
+  fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+    set_nonblocking(self.0, nonblocking)?;
+    self.1.store(nonblocking, Ordering::Relaxed);
+    Ok(())
+  }
+}
+
+impl Drop for DatagramSocket {
+  fn drop(&mut self) {
+    self.close().unwrap()
+  }
+}
+
+// ----- End DatagramSocket ------
+
+// ----- Start UdpSocket ------
+
+pub struct UdpSocket<T: DatagramLike> {
+  inner: T,
+}
+
+impl<T: DatagramLike> UdpSocket<T> {
+  /// Sends one datagram to the address given to `connect`.
+  pub fn send(&self, buf: &[u8]) -> IoResult<usize> {
+    self.inner.send(buf)
+  }
+
+  /// Receives one datagram from the address given to `connect`.
+  pub fn recv(&self, buf: &mut [u8]) -> IoResult<usize> {
+    self.inner.recv(buf)
+  }
+
+  /// Receives one datagram along with the address it came from. Whatever
+  /// does not fit in `buf` is discarded.
+  pub fn recv_from(&self, buf: &mut [u8]) -> IoResult<(usize, T::Addr)> {
+    self.inner.recv_from(buf)
+  }
+
+  pub fn local_addr(&self) -> IoResult<T::Addr> {
+    self.inner.get_sock_name()
+  }
+
+  /// The address given to `connect`.
+  pub fn peer_addr(&self) -> IoResult<T::Addr> {
+    self.inner.get_peer_name()
+  }
+
+  /// See `DatagramLike::join_multicast_v4`.
+  pub fn join_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> IoResult<()> {
+    self.inner.join_multicast_v4(group, interface)
+  }
+
+  pub fn leave_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> IoResult<()> {
+    self.inner.leave_multicast_v4(group, interface)
+  }
+
+  /// See `DatagramLike::join_multicast_v6`.
+  pub fn join_multicast_v6(&self, group: Ipv6Addr, interface: u32) -> IoResult<()> {
+    self.inner.join_multicast_v6(group, interface)
+  }
+
+  pub fn leave_multicast_v6(&self, group: Ipv6Addr, interface: u32) -> IoResult<()> {
+    self.inner.leave_multicast_v6(group, interface)
+  }
+
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+    self.inner.set_read_timeout(timeout)
+  }
+
+  pub fn set_write_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+    self.inner.set_write_timeout(timeout)
+  }
+
+  pub fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+    self.inner.set_nonblocking(nonblocking)
+  }
+}
+
+impl<T: DatagramLike<Addr = SocketAddr>> UdpSocket<T> {
+  /// Binds to the first of the resolved addresses that works, IPv4 or IPv6.
+  pub fn bind(addr: impl ToSocketAddrs) -> IoResult<UdpSocket<T>> {
+    let mut last_err = None;
+
+    for addr in addr.to_socket_addrs()? {
+      let result = T::new(family(&addr)).and_then(|mut socket| {
+        socket.bind(addr)?;
+        Ok(UdpSocket { inner: *socket })
+      });
+      match result {
+        Ok(socket) => return Ok(socket),
+        Err(err) => last_err = Some(err),
+      }
+    }
+    Err(last_err.unwrap_or_else(no_addresses))
+  }
+
+  /// Sets the default destination for `send` and filters what `recv`
+  /// returns to datagrams from there. Uses the first resolved address that
+  /// works.
+  pub fn connect(&mut self, addr: impl ToSocketAddrs) -> IoResult<()> {
+    let mut last_err = None;
+
+    for addr in addr.to_socket_addrs()? {
+      match self.inner.connect(addr) {
+        Ok(()) => return Ok(()),
+        Err(err) => last_err = Some(err),
+      }
+    }
+    Err(last_err.unwrap_or_else(no_addresses))
+  }
+
+  /// Sends one datagram to the first address `addr` resolves to.
+  pub fn send_to(&self, buf: &[u8], addr: impl ToSocketAddrs) -> IoResult<usize> {
+    match addr.to_socket_addrs()?.next() {
+      Some(addr) => self.inner.send_to(buf, addr),
+      None => Err(no_addresses()),
+    }
+  }
+}
+
+fn family(addr: &SocketAddr) -> AddressFamily {
+  if addr.is_ipv4() {
+    AddressFamily::Inet
+  } else {
+    AddressFamily::Inet6
+  }
+}
+
+fn no_addresses() -> Error {
+  Error::new(
+    ErrorKind::InvalidInput,
+    "could not resolve to any addresses",
+  )
+}
+
+impl<T: DatagramLike + AsRawFd> AsRawFd for UdpSocket<T> {
+  fn as_raw_fd(&self) -> RawFd {
+    self.inner.as_raw_fd()
+  }
+}
+
+// ----- End UdpSocket ------
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cell::RefCell;
+  use std::collections::VecDeque;
+
+  /// Loops every datagram sent back to the next receive.
+  struct LoopbackDatagram {
+    local: SocketAddr,
+    peer: Option<SocketAddr>,
+    queue: RefCell<VecDeque<(Vec<u8>, SocketAddr)>>,
+  }
+
+  impl DatagramLike for LoopbackDatagram {
+    type Addr = SocketAddr;
+
+    fn new(_family: AddressFamily) -> IoResult<Box<LoopbackDatagram>> {
+      Ok(Box::new(LoopbackDatagram {
+        local: "0.0.0.0:0".parse().unwrap(),
+        peer: None,
+        queue: RefCell::new(VecDeque::new()),
+      }))
+    }
+
+    fn bind(&mut self, addr: SocketAddr) -> IoResult<()> {
+      self.local = addr;
+      Ok(())
+    }
+
+    fn connect(&mut self, addr: SocketAddr) -> IoResult<()> {
+      self.peer = Some(addr);
+      Ok(())
+    }
+
+    fn get_peer_name(&self) -> IoResult<SocketAddr> {
+      self
+        .peer
+        .ok_or_else(|| Error::from(ErrorKind::NotConnected))
+    }
+
+    fn get_sock_name(&self) -> IoResult<SocketAddr> {
+      Ok(self.local)
+    }
+
+    fn close(&self) -> IoResult<()> {
+      Ok(())
+    }
+
+    fn send(&self, buf: &[u8]) -> IoResult<usize> {
+      self.send_to(buf, self.get_peer_name()?)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> IoResult<usize> {
+      self.recv_from(buf).map(|(read, _)| read)
+    }
+
+    fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> IoResult<usize> {
+      self
+        .queue
+        .borrow_mut()
+        .push_back((buf.to_vec(), self.local));
+      Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+      let (datagram, from) = self
+        .queue
+        .borrow_mut()
+        .pop_front()
+        .ok_or_else(|| Error::from(ErrorKind::WouldBlock))?;
+      let read = datagram.len().min(buf.len());
+      buf[..read].copy_from_slice(&datagram[..read]);
+      Ok((read, from))
+    }
+
+    fn join_multicast_v4(&self, _group: Ipv4Addr, _interface: Ipv4Addr) -> IoResult<()> {
+      Ok(())
+    }
+
+    fn leave_multicast_v4(&self, _group: Ipv4Addr, _interface: Ipv4Addr) -> IoResult<()> {
+      Ok(())
+    }
+
+    fn join_multicast_v6(&self, _group: Ipv6Addr, _interface: u32) -> IoResult<()> {
+      Ok(())
+    }
+
+    fn leave_multicast_v6(&self, _group: Ipv6Addr, _interface: u32) -> IoResult<()> {
+      Ok(())
+    }
+
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> IoResult<()> {
+      Ok(())
+    }
+
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> IoResult<()> {
+      Ok(())
+    }
+
+    fn set_nonblocking(&self, _nonblocking: bool) -> IoResult<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn works_through_the_datagram_trait() {
+    let mut socket = UdpSocket::<LoopbackDatagram>::bind("127.0.0.1:53").unwrap();
+    socket.send_to(b"query", "127.0.0.1:9").unwrap();
+    let mut buf = [0; 3];
+    let (read, from) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!((&buf[..read], from.port()), (&b"que"[..], 53));
+
+    assert!(socket.send(b"unconnected").is_err());
+    socket.connect("127.0.0.1:9").unwrap();
+    socket.send(b"ok").unwrap();
+    assert_eq!(socket.recv(&mut buf).unwrap(), 2);
+  }
+
+  #[test]
+  fn exchanges_datagrams() {
+    let server = UdpSocket::<DatagramSocket>::bind("127.0.0.1:0").unwrap();
+    let mut client = UdpSocket::<DatagramSocket>::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    client.send_to(b"ping", server_addr).unwrap();
+    let mut buf = [0; 16];
+    let (read, from) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..read], b"ping");
+    assert_eq!(from, client.local_addr().unwrap());
+
+    client.connect(server_addr).unwrap();
+    assert_eq!(client.peer_addr().unwrap(), server_addr);
+    server.send_to(b"pong", from).unwrap();
+    assert_eq!(client.recv(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"pong");
+  }
+
+  #[test]
+  fn receive_timeout_surfaces_as_timed_out() {
+    let socket = UdpSocket::<DatagramSocket>::bind("[::1]:0").unwrap();
+    socket
+      .set_read_timeout(Some(Duration::from_millis(20)))
+      .unwrap();
+    let err = socket.recv_from(&mut [0; 8]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+    socket.set_nonblocking(true).unwrap();
+    let err = socket.recv_from(&mut [0; 8]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+  }
+
+  #[test]
+  fn joins_and_leaves_multicast_groups() {
+    let v4 = UdpSocket::<DatagramSocket>::bind("0.0.0.0:0").unwrap();
+    let group = Ipv4Addr::new(239, 255, 0, 1);
+    v4.join_multicast_v4(group, Ipv4Addr::LOCALHOST).unwrap();
+    v4.leave_multicast_v4(group, Ipv4Addr::LOCALHOST).unwrap();
+    assert!(v4.leave_multicast_v4(group, Ipv4Addr::LOCALHOST).is_err());
+
+    let v6 = UdpSocket::<DatagramSocket>::bind("[::]:0").unwrap();
+    let group = "ff02::1:3".parse().unwrap();
+    v6.join_multicast_v6(group, 1).unwrap();
+    v6.leave_multicast_v6(group, 1).unwrap();
+  }
+}
@@ -0,0 +1,449 @@
+use super::options::{SocketOptions, DEFAULT_BACKLOG};
+use super::tcp::{Socket, SocketLike, TcpListener, TcpStream};
+use super::util::{connect_timeout, into_io_error};
+use nix::libc::{self, c_int, sockaddr, sockaddr_un, socklen_t};
+use nix::sys::socket::{getsockopt, sockopt, AddressFamily, SockFlag};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File, Permissions};
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::mem;
+use std::net::Shutdown;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The address of a Unix domain socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnixSocketAddr {
+  /// A socket file in the filesystem.
+  Path(PathBuf),
+  /// A name in Linux's abstract namespace, without the leading NUL byte.
+  /// These never appear in the filesystem and vanish with the socket.
+  Abstract(Vec<u8>),
+  /// A socket that was never bound, like most client ends.
+  Unnamed,
+}
+
+impl UnixSocketAddr {
+  fn from_raw(raw: &sockaddr_un, len: socklen_t) -> Self {
+    let path_len = (len as usize).saturating_sub(mem::offset_of!(sockaddr_un, sun_path));
+    let bytes: Vec<u8> = raw.sun_path[..path_len.min(raw.sun_path.len())]
+      .iter()
+      .map(|&c| c as u8)
+      .collect();
+
+    match bytes.split_first() {
+      None => UnixSocketAddr::Unnamed,
+      Some((0, name)) => UnixSocketAddr::Abstract(name.to_vec()),
+      Some(_) => {
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        UnixSocketAddr::Path(PathBuf::from(OsStr::from_bytes(&bytes[..end])))
+      }
+    }
+  }
+
+  fn to_raw(&self) -> IoResult<(sockaddr_un, socklen_t)> {
+    let mut raw: sockaddr_un = unsafe { mem::zeroed() };
+    raw.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    // Paths need room for their terminating NUL, abstract names for the
+    // leading one.
+    let (bytes, start, len) = match self {
+      UnixSocketAddr::Path(path) => {
+        let bytes = path.as_os_str().as_bytes();
+        (bytes, 0, bytes.len() + 1)
+      }
+      UnixSocketAddr::Abstract(name) => (&name[..], 1, name.len() + 1),
+      UnixSocketAddr::Unnamed => {
+        return Err(Error::new(
+          ErrorKind::InvalidInput,
+          "cannot use an unnamed address",
+        ))
+      }
+    };
+    if len > raw.sun_path.len() || (start == 0 && bytes.contains(&0)) {
+      return Err(Error::new(
+        ErrorKind::InvalidInput,
+        "invalid unix socket address",
+      ));
+    }
+    for (i, byte) in bytes.iter().enumerate() {
+      raw.sun_path[start + i] = *byte as libc::c_char;
+    }
+
+    let len = mem::offset_of!(sockaddr_un, sun_path) + len;
+    Ok((raw, len as socklen_t))
+  }
+}
+
+impl fmt::Display for UnixSocketAddr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      UnixSocketAddr::Path(path) => write!(f, "{}", path.display()),
+      UnixSocketAddr::Abstract(name) => write!(f, "@{}", OsStr::from_bytes(name).to_string_lossy()),
+      UnixSocketAddr::Unnamed => write!(f, "(unnamed)"),
+    }
+  }
+}
+
+/// The process on the other end of a Unix socket, as recorded by the kernel
+/// when it connected (`SO_PEERCRED`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+  pid: i32,
+  uid: u32,
+  gid: u32,
+}
+
+impl PeerCredentials {
+  pub fn pid(&self) -> i32 {
+    self.pid
+  }
+
+  pub fn uid(&self) -> u32 {
+    self.uid
+  }
+
+  pub fn gid(&self) -> u32 {
+    self.gid
+  }
+}
+
+// ----- Begin: UnixSocket ------
+
+/// A Unix domain stream socket.
+pub struct UnixSocket(Socket);
+
+impl UnixSocket {
+  pub fn peer_credentials(&self) -> IoResult<PeerCredentials> {
+    let creds = getsockopt(self.as_raw_fd(), sockopt::PeerCredentials).map_err(into_io_error)?;
+    Ok(PeerCredentials {
+      pid: creds.pid(),
+      uid: creds.uid(),
+      gid: creds.gid(),
+    })
+  }
+}
+
+impl AsRawFd for UnixSocket {
+  fn as_raw_fd(&self) -> RawFd {
+    self.0.as_raw_fd()
+  }
+}
+
+impl SocketLike for UnixSocket {
+  type Addr = UnixSocketAddr;
+
+  fn new(family: AddressFamily) -> IoResult<Box<UnixSocket>> {
+    if family != AddressFamily::Unix {
+      return Err(Error::new(
+        ErrorKind::InvalidInput,
+        "unix sockets only support AddressFamily::Unix",
+      ));
+    }
+    Ok(Box::new(UnixSocket(*Socket::new(family)?)))
+  }
+
+  fn accept(&self, flags: SockFlag) -> IoResult<Box<UnixSocket>> {
+    Ok(Box::new(UnixSocket(*self.0.accept(flags)?)))
+  }
+
+  fn get_peer_name(&self) -> IoResult<UnixSocketAddr> {
+    socket_name(self.as_raw_fd(), libc::getpeername)
+  }
+
+  fn get_sock_name(&self) -> IoResult<UnixSocketAddr> {
+    socket_name(self.as_raw_fd(), libc::getsockname)
+  }
+
+  fn bind(&mut self, addr: UnixSocketAddr) -> IoResult<()> {
+    let (raw, len) = addr.to_raw()?;
+    let ret = unsafe { libc::bind(self.as_raw_fd(), &raw as *const _ as *const sockaddr, len) };
+    cvt(ret)
+  }
+
+  fn connect(&mut self, addr: UnixSocketAddr, timeout: Option<Duration>) -> IoResult<()> {
+    let fd = self.as_raw_fd();
+    connect_timeout(fd, timeout, || connect(fd, &addr))
+  }
+
+  fn listen(&self, backlog: usize) -> IoResult<()> {
+    self.0.listen(backlog)
+  }
+
+  fn shutdown(&self, how: Shutdown) -> IoResult<()> {
+    self.0.shutdown(how)
+  }
+
+  fn try_clone(&self) -> IoResult<Box<UnixSocket>> {
+    Ok(Box::new(UnixSocket(*self.0.try_clone()?)))
+  }
+
+  fn close(&self) -> IoResult<()> {
+    self.0.close()
+  }
+
+  fn read(&self, buf: &mut [u8]) -> IoResult<usize> {
+    self.0.read(buf)
+  }
+
+  fn write(&self, buf: &[u8]) -> IoResult<usize> {
+    self.0.write(buf)
+  }
+
+  fn send_file(&self, file: &File, offset: u64, count: usize) -> IoResult<usize> {
+    self.0.send_file(file, offset, count)
+  }
+
+  fn poll_read(&self, timeout: Duration) -> IoResult<bool> {
+    self.0.poll_read(timeout)
+  }
+
+  fn set_only_v6(&self, _only_v6: bool) -> IoResult<()> {
+    Err(Error::new(ErrorKind::InvalidInput, "not an IPv6 socket"))
+  }
+
+  fn only_v6(&self) -> IoResult<bool> {
+    Err(Error::new(ErrorKind::InvalidInput, "not an IPv6 socket"))
+  }
+
+  fn set_options(&self, options: &SocketOptions) -> IoResult<()> {
+    self.0.set_options(options)
+  }
+
+  fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+    self.0.set_read_timeout(timeout)
+  }
+
+  fn set_write_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+    self.0.set_write_timeout(timeout)
+  }
+
+  fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+    self.0.set_nonblocking(nonblocking)
+  }
+}
+
+// nix's own sockaddr_un conversions are unsound on current compilers, so
+// Unix addresses go through libc directly.
+
+fn socket_name(
+  fd: RawFd,
+  getter: unsafe extern "C" fn(c_int, *mut sockaddr, *mut socklen_t) -> c_int,
+) -> IoResult<UnixSocketAddr> {
+  let mut raw: sockaddr_un = unsafe { mem::zeroed() };
+  let mut len = mem::size_of::<sockaddr_un>() as socklen_t;
+  cvt(unsafe { getter(fd, &mut raw as *mut _ as *mut sockaddr, &mut len) })?;
+  Ok(UnixSocketAddr::from_raw(&raw, len))
+}
+
+fn connect(fd: RawFd, addr: &UnixSocketAddr) -> IoResult<()> {
+  let (raw, len) = addr.to_raw()?;
+  cvt(unsafe { libc::connect(fd, &raw as *const _ as *const sockaddr, len) })
+}
+
+fn cvt(ret: c_int) -> IoResult<()> {
+  if ret == -1 {
+    return Err(Error::last_os_error());
+  }
+  Ok(())
+}
+
+// ----- End UnixSocket ------
+
+/// A connection accepted by a `UnixListener`.
+pub type UnixStream = TcpStream<UnixSocket>;
+
+impl TcpStream<UnixSocket> {
+  /// Who is on the other end of the connection.
+  pub fn peer_credentials(&self) -> IoResult<PeerCredentials> {
+    self.inner().peer_credentials()
+  }
+}
+
+// ----- Start UnixListener ------
+
+/// A listening Unix domain socket. A listener bound to a filesystem path
+/// removes the socket file again when dropped.
+pub struct UnixListener {
+  inner: TcpListener<UnixSocket>,
+  path: Option<PathBuf>,
+}
+
+impl UnixListener {
+  /// Binds to a socket file at `path`. A socket file left behind by a
+  /// process that is no longer listening is removed first; one that still
+  /// accepts connections makes this fail with `ErrorKind::AddrInUse`, and
+  /// any other kind of file is never touched.
+  pub fn bind(path: impl AsRef<Path>) -> IoResult<UnixListener> {
+    let path = path.as_ref();
+    remove_stale_socket(path)?;
+    let mut listener = Self::bind_addr(UnixSocketAddr::Path(path.to_path_buf()))?;
+    listener.path = Some(path.to_path_buf());
+    Ok(listener)
+  }
+
+  /// Binds to `name` in Linux's abstract socket namespace.
+  pub fn bind_abstract(name: impl AsRef<[u8]>) -> IoResult<UnixListener> {
+    Self::bind_addr(UnixSocketAddr::Abstract(name.as_ref().to_vec()))
+  }
+
+  fn bind_addr(addr: UnixSocketAddr) -> IoResult<UnixListener> {
+    let mut sock = *UnixSocket::new(AddressFamily::Unix)?;
+    sock.bind(addr)?;
+    sock.listen(DEFAULT_BACKLOG)?;
+    Ok(UnixListener {
+      inner: TcpListener::from_inner(sock),
+      path: None,
+    })
+  }
+
+  /// Sets the permission bits of the socket file, e.g. `0o660` to only let
+  /// the owner and group connect.
+  pub fn set_permissions(&self, mode: u32) -> IoResult<()> {
+    match &self.path {
+      Some(path) => fs::set_permissions(path, Permissions::from_mode(mode)),
+      None => Err(Error::new(
+        ErrorKind::InvalidInput,
+        "abstract sockets have no permissions",
+      )),
+    }
+  }
+
+  pub fn accept(&self) -> IoResult<(UnixStream, UnixSocketAddr)> {
+    self.inner.accept()
+  }
+
+  /// See `TcpListener::accept_with`.
+  pub fn accept_with(&self, flags: SockFlag) -> IoResult<(UnixStream, UnixSocketAddr)> {
+    self.inner.accept_with(flags)
+  }
+
+  /// Waits up to `timeout` for a connection to be ready to accept.
+  pub fn poll_accept(&self, timeout: Duration) -> IoResult<bool> {
+    self.inner.poll_accept(timeout)
+  }
+
+  pub fn local_addr(&self) -> IoResult<UnixSocketAddr> {
+    self.inner.local_addr()
+  }
+
+  pub fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+    self.inner.set_nonblocking(nonblocking)
+  }
+}
+
+impl AsRawFd for UnixListener {
+  fn as_raw_fd(&self) -> RawFd {
+    self.inner.as_raw_fd()
+  }
+}
+
+impl Drop for UnixListener {
+  fn drop(&mut self) {
+    if let Some(path) = &self.path {
+      let _ = fs::remove_file(path);
+    }
+  }
+}
+
+// ----- End UnixListener ------
+
+/// Removes the socket file at `path` if nobody is listening on it anymore.
+fn remove_stale_socket(path: &Path) -> IoResult<()> {
+  let metadata = match fs::symlink_metadata(path) {
+    Ok(metadata) => metadata,
+    Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+    Err(err) => return Err(err),
+  };
+  if !metadata.file_type().is_socket() {
+    return Err(Error::new(
+      ErrorKind::AlreadyExists,
+      format!("{} exists and is not a socket", path.display()),
+    ));
+  }
+
+  let probe = UnixSocket::new(AddressFamily::Unix)?;
+  match connect(probe.as_raw_fd(), &UnixSocketAddr::Path(path.to_path_buf())) {
+    Ok(()) => Err(Error::new(
+      ErrorKind::AddrInUse,
+      format!("{} is in use by another process", path.display()),
+    )),
+    Err(ref err) if err.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path),
+    Err(err) => Err(err),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{Read, Write};
+  use std::os::unix::net::UnixStream as StdUnixStream;
+
+  fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("scratch-{}-{}.sock", name, std::process::id()))
+  }
+
+  #[test]
+  fn talks_over_a_socket_file() {
+    let path = temp_path("file");
+    let listener = UnixListener::bind(&path).unwrap();
+    listener.set_permissions(0o600).unwrap();
+    assert_eq!(
+      fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+      0o600
+    );
+    assert_eq!(
+      listener.local_addr().unwrap(),
+      UnixSocketAddr::Path(path.clone())
+    );
+
+    let mut client = StdUnixStream::connect(&path).unwrap();
+    let (mut stream, peer) = listener.accept().unwrap();
+    assert_eq!(peer, UnixSocketAddr::Unnamed);
+
+    let creds = stream.peer_credentials().unwrap();
+    assert_eq!(creds.pid(), std::process::id() as i32);
+    assert_eq!(creds.uid(), nix::unistd::getuid().as_raw());
+
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    drop(listener);
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn replaces_stale_sockets_only() {
+    let path = temp_path("stale");
+    // A socket file nobody listens on any more.
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let listener = UnixListener::bind(&path).unwrap();
+    let err = UnixListener::bind(&path).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::AddrInUse);
+    drop(listener);
+
+    let file = temp_path("regular");
+    fs::write(&file, b"keep me").unwrap();
+    assert!(UnixListener::bind(&file).is_err());
+    assert_eq!(fs::read(&file).unwrap(), b"keep me");
+    fs::remove_file(&file).unwrap();
+  }
+
+  #[test]
+  fn binds_abstract_names() {
+    let name = format!("scratch-abstract-{}", std::process::id());
+    let listener = UnixListener::bind_abstract(&name).unwrap();
+    assert_eq!(
+      listener.local_addr().unwrap(),
+      UnixSocketAddr::Abstract(name.into_bytes())
+    );
+  }
+}
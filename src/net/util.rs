@@ -1,5 +1,165 @@
-use std::io::Error;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::libc::{self, c_int, c_void, socklen_t};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{getsockopt, sockopt};
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
 
 pub fn into_io_error(err: nix::Error) -> Error {
   Error::from(err.as_errno().unwrap())
 }
+
+/// Switches `fd` in or out of `O_NONBLOCK` mode.
+pub(crate) fn set_nonblocking(fd: RawFd, nonblocking: bool) -> Result<(), Error> {
+  let flags = fcntl(fd, FcntlArg::F_GETFL).map_err(into_io_error)?;
+  let mut flags = OFlag::from_bits_truncate(flags);
+  flags.set(OFlag::O_NONBLOCK, nonblocking);
+  fcntl(fd, FcntlArg::F_SETFL(flags)).map_err(into_io_error)?;
+  Ok(())
+}
+
+/// Whether `fd` is in `O_NONBLOCK` mode.
+pub(crate) fn is_nonblocking(fd: RawFd) -> Result<bool, Error> {
+  let flags = fcntl(fd, FcntlArg::F_GETFL).map_err(into_io_error)?;
+  Ok(OFlag::from_bits_truncate(flags).contains(OFlag::O_NONBLOCK))
+}
+
+/// A blocking socket only reports `EAGAIN` when a read or write timeout
+/// expired; say so instead of `WouldBlock`.
+pub(crate) fn check_timeout(nonblocking: bool, err: Error) -> Error {
+  if err.kind() == ErrorKind::WouldBlock && !nonblocking {
+    return Error::new(ErrorKind::TimedOut, "socket operation timed out");
+  }
+  err
+}
+
+/// Converts a timeout to the `timeval` `SO_RCVTIMEO` and `SO_SNDTIMEO`
+/// expect, where zero means no timeout.
+pub(crate) fn timeout_value(timeout: Option<Duration>) -> Result<TimeVal, Error> {
+  match timeout {
+    Some(timeout) if timeout == Duration::from_secs(0) => Err(Error::new(
+      ErrorKind::InvalidInput,
+      "cannot set a zero timeout",
+    )),
+    // Round up so a tiny timeout does not turn into "no timeout".
+    Some(timeout) => Ok(TimeVal::microseconds(
+      (timeout.as_micros().max(1)).min(i64::MAX as u128) as i64,
+    )),
+    None => Ok(TimeVal::microseconds(0)),
+  }
+}
+
+/// Runs `connect` on the socket `fd`, giving up with `ErrorKind::TimedOut`
+/// once `timeout` elapsed. Without a timeout it simply blocks.
+pub(crate) fn connect_timeout<F>(
+  fd: RawFd,
+  timeout: Option<Duration>,
+  connect: F,
+) -> Result<(), Error>
+where
+  F: FnOnce() -> Result<(), Error>,
+{
+  let timeout = match timeout {
+    Some(timeout) => timeout,
+    None => return connect(),
+  };
+  if timeout == Duration::from_secs(0) {
+    return Err(Error::new(
+      ErrorKind::InvalidInput,
+      "cannot set a zero timeout",
+    ));
+  }
+
+  let was_nonblocking = is_nonblocking(fd)?;
+  set_nonblocking(fd, true)?;
+  let result = match connect() {
+    Ok(()) => Ok(()),
+    Err(ref err)
+      if err.raw_os_error() == Some(libc::EINPROGRESS) || err.kind() == ErrorKind::WouldBlock =>
+    {
+      wait_connected(fd, Instant::now() + timeout)
+    }
+    Err(err) => Err(err),
+  };
+  set_nonblocking(fd, was_nonblocking)?;
+  result
+}
+
+/// Waits for a non-blocking connect on `fd` to finish by `deadline`.
+fn wait_connected(fd: RawFd, deadline: Instant) -> Result<(), Error> {
+  loop {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining == Duration::from_secs(0) {
+      return Err(Error::new(ErrorKind::TimedOut, "connection timed out"));
+    }
+    // Round up so the last sub-millisecond is waited for as well.
+    let millis = remaining.as_micros().div_ceil(1000);
+    let mut fds = [PollFd::new(fd, PollFlags::POLLOUT)];
+    match poll(&mut fds, millis.min(i32::MAX as u128) as i32) {
+      Ok(0) => continue,
+      Ok(_) => break,
+      Err(err) if err.as_errno() == Some(Errno::EINTR) => continue,
+      Err(err) => return Err(into_io_error(err)),
+    }
+  }
+
+  match getsockopt(fd, sockopt::SocketError).map_err(into_io_error)? {
+    0 => Ok(()),
+    errno => Err(Error::from_raw_os_error(errno)),
+  }
+}
+
+/// Sets an integer socket option nix has no wrapper for.
+pub(crate) fn setsockopt_int(
+  fd: RawFd,
+  level: c_int,
+  name: c_int,
+  value: c_int,
+) -> Result<(), Error> {
+  setsockopt_raw(fd, level, name, &value)
+}
+
+/// Sets a socket option nix has no wrapper for to the C struct `value`.
+pub(crate) fn setsockopt_raw<T>(
+  fd: RawFd,
+  level: c_int,
+  name: c_int,
+  value: &T,
+) -> Result<(), Error> {
+  let ret = unsafe {
+    libc::setsockopt(
+      fd,
+      level,
+      name,
+      value as *const T as *const c_void,
+      mem::size_of::<T>() as socklen_t,
+    )
+  };
+  if ret == -1 {
+    return Err(Error::last_os_error());
+  }
+  Ok(())
+}
+
+/// Reads an integer socket option nix has no wrapper for.
+pub(crate) fn getsockopt_int(fd: RawFd, level: c_int, name: c_int) -> Result<c_int, Error> {
+  let mut value: c_int = 0;
+  let mut len = mem::size_of::<c_int>() as socklen_t;
+  let ret = unsafe {
+    libc::getsockopt(
+      fd,
+      level,
+      name,
+      &mut value as *mut c_int as *mut c_void,
+      &mut len,
+    )
+  };
+  if ret == -1 {
+    return Err(Error::last_os_error());
+  }
+  Ok(value)
+}